@@ -0,0 +1,163 @@
+use crate::schema::{self, Strategy};
+use crate::Error;
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{ConnectOptions, Connection, SqlitePool};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// how often a backup's progress is reported
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// the vfs backups of in-memory databases are written with.
+/// `vacuum into` otherwise writes with the database's own vfs,
+/// which for memdb means into memory.
+#[cfg(unix)]
+const FILE_VFS: &str = "unix";
+#[cfg(windows)]
+const FILE_VFS: &str = "win32";
+
+/// how far a backup has got, in pages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackupProgress {
+    /// an estimate of the number of pages written to the backup so far,
+    /// going by the size of the backup file.
+    /// sqlite doesn't report `vacuum into`'s progress, and may not write
+    /// the file in order, so this is only exact once the backup is done.
+    pub copied: u64,
+    /// the number of pages the backup will have, going by the pages
+    /// the database uses when the backup starts
+    pub total: u64,
+}
+
+/// write a compacted copy of the database to `path` with `vacuum into`,
+/// calling `progress` as it goes.
+/// the copy is made from a single read transaction, so it is a consistent
+/// snapshot and, in WAL mode, doesn't block writers.
+pub(crate) async fn backup<F>(pool: &SqlitePool, path: &Path, mut progress: F) -> Result<(), Error>
+where
+    F: FnMut(BackupProgress),
+{
+    let mut conn = pool.acquire().await?;
+
+    let (page_size,): (u64,) = sqlx::query_as("pragma page_size")
+        .fetch_one(&mut *conn)
+        .await?;
+
+    let (total,): (u64,) = sqlx::query_as(
+        "
+        select
+            page_count - freelist_count
+        from pragma_page_count, pragma_freelist_count
+        ",
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    // in-memory databases have no file
+    let (file,): (String,) = sqlx::query_as(
+        "
+        select
+            file
+        from pragma_database_list
+        where name = 'main'
+        ",
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    // sqlite takes the path as text
+    let into = path
+        .to_str()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "backup paths must be valid UTF-8",
+            )
+        })?
+        .to_string();
+
+    let into = if file.is_empty() {
+        format!("file:{}?vfs={FILE_VFS}", uri_escape(&into))
+    } else {
+        into
+    };
+
+    let vacuum = sqlx::query("vacuum into ?").bind(into).execute(&mut *conn);
+    let mut vacuum = std::pin::pin!(vacuum);
+
+    let mut ticks = tokio::time::interval(PROGRESS_INTERVAL);
+
+    loop {
+        tokio::select! {
+            result = &mut vacuum => {
+                result?;
+                break;
+            }
+            _ = ticks.tick() => {
+                let written = tokio::fs::metadata(path)
+                    .await
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0);
+
+                progress(BackupProgress {
+                    copied: (written / page_size).min(total),
+                    total,
+                });
+            }
+        }
+    }
+
+    progress(BackupProgress {
+        copied: total,
+        total,
+    });
+
+    Ok(())
+}
+
+/// replace the database at `path` with the backup at `backup`,
+/// after checking that the backup is a database of `strategy`.
+/// the backup is copied next to `path` and renamed over it,
+/// so `path` is never left half written.
+pub(crate) async fn restore(backup: &Path, path: &Path, strategy: Strategy) -> Result<(), Error> {
+    let mut conn = SqliteConnectOptions::new()
+        .filename(backup)
+        .read_only(true)
+        .connect()
+        .await?;
+
+    schema::check_backup(&mut conn, strategy).await?;
+
+    conn.close().await?;
+
+    let restoring = with_suffix(path, ".restoring");
+
+    tokio::fs::copy(backup, &restoring).await?;
+    tokio::fs::File::open(&restoring).await?.sync_all().await?;
+
+    // the old database's WAL must not be applied to the backup
+    for suffix in ["-wal", "-shm"] {
+        match tokio::fs::remove_file(with_suffix(path, suffix)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    tokio::fs::rename(&restoring, path).await?;
+
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+/// escape the characters that are special in the path of a sqlite URI
+fn uri_escape(path: &str) -> String {
+    path.replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23")
+}
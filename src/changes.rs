@@ -0,0 +1,188 @@
+use crate::{Codec, Error, EventKind};
+use serde::de::DeserializeOwned;
+use sqlx::SqliteConnection;
+
+/// a committed change, as recorded in the change log
+#[derive(Clone, Debug)]
+pub struct Change {
+    seq: u64,
+    kind: EventKind,
+    bucket: String,
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    codec: Codec,
+}
+
+impl Change {
+    /// the change's position in the change log.
+    /// later commits have greater sequence numbers.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// whether the key was written or deleted
+    pub fn kind(&self) -> EventKind {
+        self.kind
+    }
+
+    /// the bucket of the key that changed
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// the key that changed
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// the value written, or `None` for a delete
+    pub fn value<V>(&self) -> Result<Option<V>, Error>
+    where
+        V: DeserializeOwned,
+    {
+        self.value
+            .as_deref()
+            .map(|value_bytes| self.codec.deserialize(value_bytes))
+            .transpose()
+    }
+}
+
+/// seq, bucket, key, kind and value
+type ChangeRow = (i64, String, Vec<u8>, String, Option<Vec<u8>>);
+
+/// create the change log if `enable` is set.
+/// once created, every handle records changes in it, whether or not
+/// it was opened with `enable`.
+pub(crate) async fn open(conn: &mut SqliteConnection, enable: bool) -> Result<(), Error> {
+    if enable {
+        sqlx::query(
+            "
+            create table if not exists changes (
+                -- autoincrement so sequence numbers are never reused,
+                -- even after the log is truncated
+                seq integer primary key autoincrement,
+                bucket text not null,
+                key blob not null,
+                kind text not null,
+                -- null for deletes
+                value blob,
+                committed_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+            )
+            ",
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// whether the database has a change log.
+/// another handle can create it at any time, so writes check
+/// in each transaction.
+pub(crate) async fn exists(conn: &mut SqliteConnection) -> Result<bool, Error> {
+    let (exists,): (bool,) = sqlx::query_as(
+        "
+        select
+            count(*) > 0
+        from sqlite_master
+        where type = 'table'
+        and name = 'changes'
+        ",
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(exists)
+}
+
+/// append a change to the log, in the same transaction as the change itself
+pub(crate) async fn record(
+    conn: &mut SqliteConnection,
+    kind: EventKind,
+    bucket: &str,
+    key: &[u8],
+    value: Option<&[u8]>,
+) -> Result<(), Error> {
+    sqlx::query(
+        "
+        insert into changes (bucket, key, kind, value)
+        values(?, ?, ?, ?)
+        ",
+    )
+    .bind(bucket)
+    .bind(key)
+    .bind(kind_name(kind))
+    .bind(value)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// changes with sequence numbers greater than `after`, oldest first,
+/// up to `limit` of them
+pub(crate) async fn after(
+    conn: &mut SqliteConnection,
+    codec: Codec,
+    after: u64,
+    limit: u32,
+) -> Result<Vec<Change>, Error> {
+    let rows: Vec<ChangeRow> = sqlx::query_as(
+        "
+        select
+            seq,
+            bucket,
+            key,
+            kind,
+            value
+        from changes
+        where seq > ?
+        order by seq
+        limit ?
+        ",
+    )
+    .bind(i64::try_from(after).unwrap_or(i64::MAX))
+    .bind(limit)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(seq, bucket, key, kind, value)| Change {
+            seq: seq as u64,
+            kind: if kind == kind_name(EventKind::Delete) {
+                EventKind::Delete
+            } else {
+                EventKind::Put
+            },
+            bucket,
+            key,
+            value,
+            codec,
+        })
+        .collect())
+}
+
+/// delete changes with sequence numbers up to and including `through`,
+/// returning how many were deleted
+pub(crate) async fn truncate(conn: &mut SqliteConnection, through: u64) -> Result<u64, Error> {
+    let result = sqlx::query(
+        "
+        delete from changes
+        where seq <= ?
+        ",
+    )
+    .bind(i64::try_from(through).unwrap_or(i64::MAX))
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+fn kind_name(kind: EventKind) -> &'static str {
+    match kind {
+        EventKind::Put => "put",
+        EventKind::Delete => "delete",
+    }
+}
@@ -0,0 +1,527 @@
+use crate::Error;
+use serde::de::{DeserializeOwned, Visitor};
+use serde::ser::{Impossible, SerializeSeq};
+use serde::{forward_to_deserialize_any, Serialize};
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::sqlite::{SqliteArgumentValue, SqliteTypeInfo};
+use sqlx::{Encode, Sqlite, Type};
+use std::fmt::Display;
+
+/// how values are serialized to and from the database
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// CBOR, via `ciborium`
+    #[default]
+    Cbor,
+    /// JSON, via `serde_json`.
+    /// values are stored as text, so sqlite's json functions
+    /// like `json_extract` can query them.
+    Json,
+    /// MessagePack with named struct fields, via `rmp-serde`
+    MessagePack,
+    /// postcard, a compact format that is not self-describing
+    Postcard,
+    /// values are stored as they are.
+    /// only byte strings (like `Vec<u8>`) and strings can be written with this codec.
+    Raw,
+}
+
+impl Codec {
+    /// the name recorded in the database's metadata
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Codec::Cbor => "cbor",
+            Codec::Json => "json",
+            Codec::MessagePack => "message_pack",
+            Codec::Postcard => "postcard",
+            Codec::Raw => "raw",
+        }
+    }
+
+    /// the codec recorded in the database's metadata as `name`
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cbor" => Some(Codec::Cbor),
+            "json" => Some(Codec::Json),
+            "message_pack" => Some(Codec::MessagePack),
+            "postcard" => Some(Codec::Postcard),
+            "raw" => Some(Codec::Raw),
+            _ => None,
+        }
+    }
+
+    /// whether values can be read without knowing their type,
+    /// which path indexes need
+    pub(crate) fn is_self_describing(self) -> bool {
+        match self {
+            Codec::Cbor | Codec::Json | Codec::MessagePack => true,
+            Codec::Postcard | Codec::Raw => false,
+        }
+    }
+
+    pub(crate) fn serialize<V>(self, value: &V) -> Result<EncodedValue, Error>
+    where
+        V: Serialize + ?Sized,
+    {
+        let encoded = match self {
+            Codec::Cbor => {
+                let mut value_bytes = vec![];
+                ciborium::into_writer(value, &mut value_bytes)
+                    .map_err(|e| Error::Serialization(e.into()))?;
+                EncodedValue::Blob(value_bytes)
+            }
+            Codec::Json => EncodedValue::Text(
+                serde_json::to_string(value).map_err(|e| Error::Serialization(e.into()))?,
+            ),
+            Codec::MessagePack => EncodedValue::Blob(
+                rmp_serde::to_vec_named(value).map_err(|e| Error::Serialization(e.into()))?,
+            ),
+            Codec::Postcard => EncodedValue::Blob(
+                postcard::to_stdvec(value).map_err(|e| Error::Serialization(e.into()))?,
+            ),
+            Codec::Raw => EncodedValue::Blob(
+                value
+                    .serialize(RawSerializer)
+                    .map_err(|e| Error::Serialization(e.into()))?,
+            ),
+        };
+
+        Ok(encoded)
+    }
+
+    pub(crate) fn deserialize<V>(self, value_bytes: &[u8]) -> Result<V, Error>
+    where
+        V: DeserializeOwned,
+    {
+        match self {
+            Codec::Cbor => {
+                ciborium::from_reader(value_bytes).map_err(|e| Error::Deserialization(e.into()))
+            }
+            Codec::Json => {
+                serde_json::from_slice(value_bytes).map_err(|e| Error::Deserialization(e.into()))
+            }
+            Codec::MessagePack => {
+                rmp_serde::from_slice(value_bytes).map_err(|e| Error::Deserialization(e.into()))
+            }
+            Codec::Postcard => {
+                postcard::from_bytes(value_bytes).map_err(|e| Error::Deserialization(e.into()))
+            }
+            Codec::Raw => V::deserialize(RawDeserializer(value_bytes))
+                .map_err(|e| Error::Deserialization(e.into())),
+        }
+    }
+}
+
+/// a serialized value, bound as a blob or as text
+pub enum EncodedValue {
+    Blob(Vec<u8>),
+    Text(String),
+}
+
+impl EncodedValue {
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match self {
+            EncodedValue::Blob(value_bytes) => value_bytes,
+            EncodedValue::Text(value) => value.as_bytes(),
+        }
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        match self {
+            EncodedValue::Blob(value_bytes) => value_bytes,
+            EncodedValue::Text(value) => value.into_bytes(),
+        }
+    }
+}
+
+impl Type<Sqlite> for EncodedValue {
+    fn type_info() -> SqliteTypeInfo {
+        <Vec<u8> as Type<Sqlite>>::type_info()
+    }
+
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <Vec<u8> as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'q> Encode<'q, Sqlite> for EncodedValue {
+    fn encode_by_ref(&self, buf: &mut Vec<SqliteArgumentValue<'q>>) -> Result<IsNull, BoxDynError> {
+        match self {
+            EncodedValue::Blob(value_bytes) => {
+                <Vec<u8> as Encode<Sqlite>>::encode_by_ref(value_bytes, buf)
+            }
+            EncodedValue::Text(value) => <String as Encode<Sqlite>>::encode_by_ref(value, buf),
+        }
+    }
+
+    fn encode(self, buf: &mut Vec<SqliteArgumentValue<'q>>) -> Result<IsNull, BoxDynError> {
+        match self {
+            EncodedValue::Blob(value_bytes) => {
+                <Vec<u8> as Encode<Sqlite>>::encode(value_bytes, buf)
+            }
+            EncodedValue::Text(value) => <String as Encode<Sqlite>>::encode(value, buf),
+        }
+    }
+
+    fn produces(&self) -> Option<SqliteTypeInfo> {
+        match self {
+            EncodedValue::Blob(_) => Some(<Vec<u8> as Type<Sqlite>>::type_info()),
+            EncodedValue::Text(_) => Some(<String as Type<Sqlite>>::type_info()),
+        }
+    }
+}
+
+/// the error for values that `Codec::Raw` can't represent
+#[derive(Debug)]
+struct RawError(String);
+
+impl Display for RawError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RawError {}
+
+impl serde::ser::Error for RawError {
+    fn custom<T: Display>(msg: T) -> Self {
+        RawError(msg.to_string())
+    }
+}
+
+impl serde::de::Error for RawError {
+    fn custom<T: Display>(msg: T) -> Self {
+        RawError(msg.to_string())
+    }
+}
+
+/// implements `Serializer` methods for types
+/// that `Codec::Raw` can't represent
+macro_rules! unsupported {
+    ($($method:ident($($arg:ty),*) -> $ret:ty;)*) => {
+        $(
+            fn $method(self, $(_: $arg),*) -> Result<$ret, Self::Error> {
+                Err(RawError(concat!(
+                    "the raw codec only supports bytes and strings, not ",
+                    stringify!($method)
+                ).to_string()))
+            }
+        )*
+    };
+}
+
+/// serializes byte strings, strings, and sequences of bytes as they are
+struct RawSerializer;
+
+impl serde::Serializer for RawSerializer {
+    type Ok = Vec<u8>;
+    type Error = RawError;
+    type SerializeSeq = RawSeqSerializer;
+    type SerializeTuple = Impossible<Vec<u8>, RawError>;
+    type SerializeTupleStruct = Impossible<Vec<u8>, RawError>;
+    type SerializeTupleVariant = Impossible<Vec<u8>, RawError>;
+    type SerializeMap = Impossible<Vec<u8>, RawError>;
+    type SerializeStruct = Impossible<Vec<u8>, RawError>;
+    type SerializeStructVariant = Impossible<Vec<u8>, RawError>;
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Ok(v.to_vec())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        Ok(v.as_bytes().to_vec())
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Ok(RawSeqSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        Err(RawError(
+            "the raw codec only supports bytes and strings, not enums".to_string(),
+        ))
+    }
+
+    unsupported! {
+        serialize_bool(bool) -> Self::Ok;
+        serialize_i8(i8) -> Self::Ok;
+        serialize_i16(i16) -> Self::Ok;
+        serialize_i32(i32) -> Self::Ok;
+        serialize_i64(i64) -> Self::Ok;
+        serialize_u8(u8) -> Self::Ok;
+        serialize_u16(u16) -> Self::Ok;
+        serialize_u32(u32) -> Self::Ok;
+        serialize_u64(u64) -> Self::Ok;
+        serialize_f32(f32) -> Self::Ok;
+        serialize_f64(f64) -> Self::Ok;
+        serialize_char(char) -> Self::Ok;
+        serialize_none() -> Self::Ok;
+        serialize_unit() -> Self::Ok;
+        serialize_unit_struct(&'static str) -> Self::Ok;
+        serialize_unit_variant(&'static str, u32, &'static str) -> Self::Ok;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+}
+
+/// collects a sequence of `u8`s, like a `Vec<u8>`
+struct RawSeqSerializer(Vec<u8>);
+
+impl SerializeSeq for RawSeqSerializer {
+    type Ok = Vec<u8>;
+    type Error = RawError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.0.push(value.serialize(ByteSerializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(self.0)
+    }
+}
+
+/// serializes a single `u8` of a sequence
+struct ByteSerializer;
+
+impl serde::Serializer for ByteSerializer {
+    type Ok = u8;
+    type Error = RawError;
+    type SerializeSeq = Impossible<u8, RawError>;
+    type SerializeTuple = Impossible<u8, RawError>;
+    type SerializeTupleStruct = Impossible<u8, RawError>;
+    type SerializeTupleVariant = Impossible<u8, RawError>;
+    type SerializeMap = Impossible<u8, RawError>;
+    type SerializeStruct = Impossible<u8, RawError>;
+    type SerializeStructVariant = Impossible<u8, RawError>;
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        Ok(v)
+    }
+
+    fn serialize_some<T>(self, _value: &T) -> Result<Self::Ok, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        Err(RawError(
+            "the raw codec only supports sequences of bytes".to_string(),
+        ))
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        Err(RawError(
+            "the raw codec only supports sequences of bytes".to_string(),
+        ))
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        Err(RawError(
+            "the raw codec only supports sequences of bytes".to_string(),
+        ))
+    }
+
+    unsupported! {
+        serialize_bool(bool) -> Self::Ok;
+        serialize_i8(i8) -> Self::Ok;
+        serialize_i16(i16) -> Self::Ok;
+        serialize_i32(i32) -> Self::Ok;
+        serialize_i64(i64) -> Self::Ok;
+        serialize_u16(u16) -> Self::Ok;
+        serialize_u32(u32) -> Self::Ok;
+        serialize_u64(u64) -> Self::Ok;
+        serialize_f32(f32) -> Self::Ok;
+        serialize_f64(f64) -> Self::Ok;
+        serialize_char(char) -> Self::Ok;
+        serialize_str(&str) -> Self::Ok;
+        serialize_bytes(&[u8]) -> Self::Ok;
+        serialize_none() -> Self::Ok;
+        serialize_unit() -> Self::Ok;
+        serialize_unit_struct(&'static str) -> Self::Ok;
+        serialize_unit_variant(&'static str, u32, &'static str) -> Self::Ok;
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+}
+
+/// deserializes stored bytes as a byte string, a string, or a sequence of bytes
+struct RawDeserializer<'a>(&'a [u8]);
+
+impl<'de> serde::Deserializer<'de> for RawDeserializer<'_> {
+    type Error = RawError;
+
+    fn deserialize_any<Vis>(self, visitor: Vis) -> Result<Vis::Value, Self::Error>
+    where
+        Vis: Visitor<'de>,
+    {
+        visitor.visit_bytes(self.0)
+    }
+
+    fn deserialize_str<Vis>(self, visitor: Vis) -> Result<Vis::Value, Self::Error>
+    where
+        Vis: Visitor<'de>,
+    {
+        let s = std::str::from_utf8(self.0).map_err(<RawError as serde::de::Error>::custom)?;
+        visitor.visit_str(s)
+    }
+
+    fn deserialize_string<Vis>(self, visitor: Vis) -> Result<Vis::Value, Self::Error>
+    where
+        Vis: Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_seq<Vis>(self, visitor: Vis) -> Result<Vis::Value, Self::Error>
+    where
+        Vis: Visitor<'de>,
+    {
+        visitor.visit_seq(serde::de::value::SeqDeserializer::new(
+            self.0.iter().copied(),
+        ))
+    }
+
+    fn deserialize_option<Vis>(self, visitor: Vis) -> Result<Vis::Value, Self::Error>
+    where
+        Vis: Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<Vis>(
+        self,
+        _name: &'static str,
+        visitor: Vis,
+    ) -> Result<Vis::Value, Self::Error>
+    where
+        Vis: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char
+        bytes byte_buf unit unit_struct tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    #[test]
+    fn roundtrips() {
+        let user = User {
+            name: "joe".to_string(),
+            age: 42,
+        };
+
+        for codec in [
+            Codec::Cbor,
+            Codec::Json,
+            Codec::MessagePack,
+            Codec::Postcard,
+        ] {
+            let encoded = codec.serialize(&user).unwrap();
+            let decoded: User = codec.deserialize(encoded.as_bytes()).unwrap();
+            assert_eq!(decoded, user, "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn json_is_text() {
+        let encoded = Codec::Json.serialize(&vec![1, 2, 3]).unwrap();
+        assert!(matches!(encoded, EncodedValue::Text(ref s) if s == "[1,2,3]"));
+    }
+
+    #[test]
+    fn raw() {
+        let encoded = Codec::Raw.serialize("hello").unwrap();
+        assert_eq!(encoded.as_bytes(), b"hello");
+        let decoded: String = Codec::Raw.deserialize(b"hello").unwrap();
+        assert_eq!(decoded, "hello");
+
+        let encoded = Codec::Raw.serialize(&vec![0u8, 1, 255]).unwrap();
+        assert_eq!(encoded.as_bytes(), &[0, 1, 255]);
+        let decoded: Vec<u8> = Codec::Raw.deserialize(&[0, 1, 255]).unwrap();
+        assert_eq!(decoded, vec![0, 1, 255]);
+
+        let encoded = Codec::Raw.serialize(&b"bytes"[..]).unwrap();
+        assert_eq!(encoded.as_bytes(), b"bytes");
+
+        let decoded: Option<String> = Codec::Raw.deserialize(b"hello").unwrap();
+        assert_eq!(decoded, Some("hello".to_string()));
+
+        assert!(matches!(
+            Codec::Raw.serialize(&42),
+            Err(Error::Serialization(_))
+        ));
+        assert!(matches!(
+            Codec::Raw.deserialize::<String>(&[0xff]),
+            Err(Error::Deserialization(_))
+        ));
+    }
+}
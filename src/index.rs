@@ -0,0 +1,475 @@
+use crate::storage::{range_condition, Storage};
+use crate::{key, Codec, Element, Error};
+use sqlx::SqliteConnection;
+use std::fmt::Debug;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+/// the encoded index value for a serialized value,
+/// or `None` if the value is not indexed
+pub(crate) type Extractor = Arc<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// the closure indexes registered with a database, shared by its handles.
+/// registrations last as long as the process, so indexes must be registered
+/// again each time the database is opened.
+/// path indexes are stored in the database instead, in `index_paths`.
+#[derive(Clone, Default)]
+pub struct Indexes(Arc<RwLock<Vec<Index>>>);
+
+struct Index {
+    bucket: String,
+    name: String,
+    extract: Extractor,
+}
+
+impl Debug for Indexes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let indexes = self.0.read().unwrap();
+        f.debug_list()
+            .entries(indexes.iter().map(|index| (&index.bucket, &index.name)))
+            .finish()
+    }
+}
+
+impl Indexes {
+    /// register an index, replacing any with the same bucket and name
+    pub(crate) fn insert(&self, bucket: &str, name: &str, extract: Extractor) {
+        let mut indexes = self.0.write().unwrap();
+        indexes.retain(|index| !(index.bucket == bucket && index.name == name));
+        indexes.push(Index {
+            bucket: bucket.to_string(),
+            name: name.to_string(),
+            extract,
+        });
+    }
+
+    /// unregister an index, returning whether it was registered
+    pub(crate) fn remove(&self, bucket: &str, name: &str) -> bool {
+        let mut indexes = self.0.write().unwrap();
+        let len = indexes.len();
+        indexes.retain(|index| !(index.bucket == bucket && index.name == name));
+        indexes.len() < len
+    }
+
+    /// the names and extractors of the indexes on a bucket
+    fn for_bucket(&self, bucket: &str) -> Vec<(String, Extractor)> {
+        let indexes = self.0.read().unwrap();
+        indexes
+            .iter()
+            .filter(|index| index.bucket == bucket)
+            .map(|index| (index.name.clone(), index.extract.clone()))
+            .collect()
+    }
+
+    /// the extractor of a registered index
+    pub(crate) fn get(&self, bucket: &str, name: &str) -> Option<Extractor> {
+        let indexes = self.0.read().unwrap();
+        indexes
+            .iter()
+            .find(|index| index.bucket == bucket && index.name == name)
+            .map(|index| index.extract.clone())
+    }
+}
+
+pub(crate) async fn open(conn: &mut SqliteConnection) -> Result<(), Error> {
+    sqlx::query(
+        "
+        create table if not exists index_entries (
+            name text not null,
+            bucket text not null,
+            value blob not null,
+            key blob not null,
+
+            primary key (name, bucket, value, key)
+        )
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "
+        create index if not exists index_entries_key on index_entries (bucket, key);
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "
+        create table if not exists index_paths (
+            bucket text not null,
+            name text not null,
+            path text not null,
+
+            primary key (bucket, name)
+        )
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// how an index computes the index value of a value
+pub(crate) enum Definition {
+    /// a closure, registered in this process only
+    Closure(Extractor),
+    /// the field at a path, stored in the database
+    Path(String),
+}
+
+/// register an index on the storage's bucket,
+/// replacing any with the same name, then build it
+pub(crate) async fn register<T>(
+    storage: &T,
+    conn: &mut SqliteConnection,
+    name: &str,
+    definition: Definition,
+) -> Result<(), Error>
+where
+    T: Storage,
+{
+    sqlx::query(
+        "
+        delete from index_paths
+        where bucket = ?
+        and name = ?
+        ",
+    )
+    .bind(storage.bucket())
+    .bind(name)
+    .execute(&mut *conn)
+    .await?;
+
+    match definition {
+        Definition::Closure(extract) => storage.indexes().insert(storage.bucket(), name, extract),
+        Definition::Path(path) => {
+            storage.indexes().remove(storage.bucket(), name);
+
+            sqlx::query(
+                "
+                insert into index_paths (bucket, name, path)
+                values(?, ?, ?)
+                ",
+            )
+            .bind(storage.bucket())
+            .bind(name)
+            .bind(path)
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+
+    rebuild(storage, conn, name).await
+}
+
+/// unregister the index named `name` on the storage's bucket,
+/// returning whether there was one
+pub(crate) async fn unregister<T>(
+    storage: &T,
+    conn: &mut SqliteConnection,
+    name: &str,
+) -> Result<bool, Error>
+where
+    T: Storage,
+{
+    let registered = storage.indexes().remove(storage.bucket(), name);
+
+    let deleted = sqlx::query(
+        "
+        delete from index_paths
+        where bucket = ?
+        and name = ?
+        ",
+    )
+    .bind(storage.bucket())
+    .bind(name)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(registered || deleted.rows_affected() > 0)
+}
+
+/// the names and extractors of the indexes on the storage's bucket,
+/// closure indexes first
+async fn extractors<T>(
+    storage: &T,
+    conn: &mut SqliteConnection,
+) -> Result<Vec<(String, Extractor)>, Error>
+where
+    T: Storage,
+{
+    let mut extractors = storage.indexes().for_bucket(storage.bucket());
+
+    let paths: Vec<(String, String)> = sqlx::query_as(
+        "
+        select
+            name,
+            path
+        from index_paths
+        where bucket = ?
+        ",
+    )
+    .bind(storage.bucket())
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for (name, path) in paths {
+        if !extractors.iter().any(|(registered, _)| *registered == name) {
+            extractors.push((name, path_extractor(storage.codec(), path)));
+        }
+    }
+
+    Ok(extractors)
+}
+
+/// the extractor of the index named `name` on the storage's bucket,
+/// or `None` if there is no such index
+pub(crate) async fn extractor<T>(
+    storage: &T,
+    conn: &mut SqliteConnection,
+    name: &str,
+) -> Result<Option<Extractor>, Error>
+where
+    T: Storage,
+{
+    if let Some(extract) = storage.indexes().get(storage.bucket(), name) {
+        return Ok(Some(extract));
+    }
+
+    let path: Option<(String,)> = sqlx::query_as(
+        "
+        select
+            path
+        from index_paths
+        where bucket = ?
+        and name = ?
+        ",
+    )
+    .bind(storage.bucket())
+    .bind(name)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(path.map(|(path,)| path_extractor(storage.codec(), path)))
+}
+
+fn path_extractor(codec: Codec, path: String) -> Extractor {
+    Arc::new(move |value_bytes| {
+        let value: serde_json::Value = codec.deserialize(value_bytes).ok()?;
+        path_element(&value, &path).map(|element| key::encode(&element))
+    })
+}
+
+/// index a key's new value in every index on its bucket
+pub(crate) async fn update<T>(
+    storage: &T,
+    conn: &mut SqliteConnection,
+    key: &[u8],
+    value_bytes: &[u8],
+) -> Result<(), Error>
+where
+    T: Storage,
+{
+    for (name, extract) in extractors(storage, conn).await? {
+        sqlx::query(
+            "
+            delete from index_entries
+            where name = ?
+            and bucket = ?
+            and key = ?
+            ",
+        )
+        .bind(&name)
+        .bind(storage.bucket())
+        .bind(key)
+        .execute(&mut *conn)
+        .await?;
+
+        if let Some(value) = extract(value_bytes) {
+            insert(conn, &name, storage.bucket(), &value, key).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// remove a key from every index on its bucket
+pub(crate) async fn remove(
+    conn: &mut SqliteConnection,
+    bucket: &str,
+    key: &[u8],
+) -> Result<(), Error> {
+    sqlx::query(
+        "
+        delete from index_entries
+        where bucket = ?
+        and key = ?
+        ",
+    )
+    .bind(bucket)
+    .bind(key)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// remove every key in a bucket from its indexes, or only from the index
+/// named `name`
+pub(crate) async fn clear(
+    conn: &mut SqliteConnection,
+    bucket: &str,
+    name: Option<&str>,
+) -> Result<(), Error> {
+    sqlx::query(
+        "
+        delete from index_entries
+        where bucket = ?
+        and (? is null or name = ?)
+        ",
+    )
+    .bind(bucket)
+    .bind(name)
+    .bind(name)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// index every current key/value in the bucket in the index named `name`
+async fn rebuild<T>(storage: &T, conn: &mut SqliteConnection, name: &str) -> Result<(), Error>
+where
+    T: Storage,
+{
+    clear(conn, storage.bucket(), Some(name)).await?;
+
+    let Some(extract) = extractor(storage, conn, name).await? else {
+        return Ok(());
+    };
+
+    for key in storage.keys(conn).await? {
+        if let Some((value_bytes, _)) = storage.current(conn, &key).await? {
+            if let Some(value) = extract(&value_bytes) {
+                insert(conn, name, storage.bucket(), &value, &key).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn insert(
+    conn: &mut SqliteConnection,
+    name: &str,
+    bucket: &str,
+    value: &[u8],
+    key: &[u8],
+) -> Result<(), Error> {
+    sqlx::query(
+        "
+        insert into index_entries (name, bucket, value, key)
+        values(?, ?, ?, ?)
+        ",
+    )
+    .bind(name)
+    .bind(bucket)
+    .bind(value)
+    .bind(key)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// the keys whose encoded index values are in the given range,
+/// in index value order and then key order
+pub(crate) async fn lookup(
+    conn: &mut SqliteConnection,
+    bucket: &str,
+    name: &str,
+    start: Bound<&[u8]>,
+    end: Bound<&[u8]>,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let (condition, binds) = range_condition("value", start, end);
+
+    let sql = format!(
+        "
+        select
+            key
+        from index_entries
+        where name = ?
+        and bucket = ?
+        and {condition}
+        order by value, key
+        "
+    );
+
+    let mut query = sqlx::query_as(&sql).bind(name).bind(bucket);
+
+    for bind in binds {
+        query = query.bind(bind);
+    }
+
+    let keys: Vec<Vec<u8>> = query
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|(key,)| key)
+        .collect();
+
+    Ok(keys)
+}
+
+/// the element at `path` in a value.
+/// whole numbers are integers whether they were serialized as integers or
+/// floats, so `31` and `31.0` are indexed the same.
+/// `path` is a `.` separated list of field names and array positions.
+/// values that are not strings, numbers, booleans or null are not indexed.
+pub(crate) fn path_element(value: &serde_json::Value, path: &str) -> Option<Element> {
+    let mut value = value;
+
+    for segment in path.split('.') {
+        value = match value {
+            serde_json::Value::Object(fields) => fields.get(segment)?,
+            serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    let element = match value {
+        serde_json::Value::Null => Element::Null,
+        serde_json::Value::Bool(b) => (*b).into(),
+        serde_json::Value::Number(number) => number_element(number)?,
+        serde_json::Value::String(string) => string.clone().into(),
+        serde_json::Value::Array(_) | serde_json::Value::Object(_) => return None,
+    };
+
+    Some(element)
+}
+
+fn number_element(number: &serde_json::Number) -> Option<Element> {
+    if let Some(int) = number.as_i64() {
+        return Some(int.into());
+    } else if let Some(uint) = number.as_u64() {
+        return Some(uint.into());
+    }
+
+    let double = number.as_f64()?;
+
+    // 2^63 and 2^64 are exact as floats, so these bounds are too
+    let element = if double.fract() != 0.0 {
+        Element::Double(double)
+    } else if (-(2f64.powi(63))..2f64.powi(63)).contains(&double) {
+        Element::Int(double as i64)
+    } else if (0.0..2f64.powi(64)).contains(&double) {
+        Element::UInt(double as u64)
+    } else {
+        Element::Double(double)
+    };
+
+    Some(element)
+}
@@ -0,0 +1,280 @@
+use crate::{Element, Error, Tuple};
+
+/// a type that can be used as a key of a `TypedDb`.
+///
+/// keys are encoded as `Tuple`s, so their encodings sort in the same order as
+/// the keys themselves, which is what makes range scans over typed keys work,
+/// and a typed key encodes the same as the `Tuple` of the same elements.
+/// rust tuples are tuples of their fields, and other keys are one element tuples.
+/// strings and bytes encode as themselves when they are the whole key,
+/// so typed and untyped handles agree on those keys.
+pub trait Key: Sized {
+    /// the key as an element of a tuple
+    fn to_element(&self) -> Element;
+
+    /// the key from an element of a tuple,
+    /// failing with `Error::KeyDecoding` if the element has another type
+    fn from_element(element: Element) -> Result<Self, Error>;
+
+    /// encode the key when it is the whole key
+    fn encode_key(&self) -> Vec<u8> {
+        Tuple(vec![self.to_element()]).pack()
+    }
+
+    /// decode a whole key encoded by `encode_key`
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+        let mut elements = Tuple::unpack(bytes)?.0;
+
+        match (elements.pop(), elements.is_empty()) {
+            (Some(element), true) => Self::from_element(element),
+            _ => Err(Error::KeyDecoding),
+        }
+    }
+}
+
+/// encode a whole key
+pub(crate) fn encode<K>(key: &K) -> Vec<u8>
+where
+    K: Key,
+{
+    key.encode_key()
+}
+
+/// decode a whole key, which must use all of `bytes`
+pub(crate) fn decode<K>(bytes: &[u8]) -> Result<K, Error>
+where
+    K: Key,
+{
+    K::decode_key(bytes)
+}
+
+impl Key for Vec<u8> {
+    fn to_element(&self) -> Element {
+        Element::Bytes(self.clone())
+    }
+
+    fn from_element(element: Element) -> Result<Self, Error> {
+        match element {
+            Element::Bytes(bytes) => Ok(bytes),
+            _ => Err(Error::KeyDecoding),
+        }
+    }
+
+    fn encode_key(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Key for String {
+    fn to_element(&self) -> Element {
+        Element::String(self.clone())
+    }
+
+    fn from_element(element: Element) -> Result<Self, Error> {
+        match element {
+            Element::String(string) => Ok(string),
+            _ => Err(Error::KeyDecoding),
+        }
+    }
+
+    fn encode_key(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::KeyDecoding)
+    }
+}
+
+/// integers are `Element::Int`s, so they sort numerically whatever their type
+macro_rules! integer_key {
+    ($($t:ty),*) => {
+        $(
+            impl Key for $t {
+                fn to_element(&self) -> Element {
+                    Element::from(*self)
+                }
+
+                fn from_element(element: Element) -> Result<Self, Error> {
+                    match element {
+                        Element::Int(int) => <$t>::try_from(int).map_err(|_| Error::KeyDecoding),
+                        Element::UInt(uint) => <$t>::try_from(uint).map_err(|_| Error::KeyDecoding),
+                        _ => Err(Error::KeyDecoding),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integer_key!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+/// rust tuples are tuples of their fields, so they sort by their
+/// first field, then their second, and so on
+macro_rules! tuple_key {
+    ($($t:ident),*) => {
+        impl<$($t),*> Key for ($($t,)*)
+        where
+            $($t: Key,)*
+        {
+            fn to_element(&self) -> Element {
+                Element::Tuple(self.to_tuple())
+            }
+
+            #[allow(non_snake_case)]
+            fn from_element(element: Element) -> Result<Self, Error> {
+                let Element::Tuple(Tuple(elements)) = element else {
+                    return Err(Error::KeyDecoding);
+                };
+
+                let mut elements = elements.into_iter();
+                $(
+                    let $t = $t::from_element(elements.next().ok_or(Error::KeyDecoding)?)?;
+                )*
+
+                if elements.next().is_some() {
+                    return Err(Error::KeyDecoding);
+                }
+
+                Ok(($($t,)*))
+            }
+
+            fn encode_key(&self) -> Vec<u8> {
+                self.to_tuple().pack()
+            }
+
+            fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+                Self::from_element(Element::Tuple(Tuple::unpack(bytes)?))
+            }
+        }
+
+        impl<$($t),*> ToTuple for ($($t,)*)
+        where
+            $($t: Key,)*
+        {
+            #[allow(non_snake_case)]
+            fn to_tuple(&self) -> Tuple {
+                let ($($t,)*) = self;
+                Tuple(vec![$($t.to_element()),*])
+            }
+        }
+    };
+}
+
+/// the `Tuple` of a rust tuple's fields
+trait ToTuple {
+    fn to_tuple(&self) -> Tuple;
+}
+
+tuple_key!(A);
+tuple_key!(A, B);
+tuple_key!(A, B, C);
+tuple_key!(A, B, C, D);
+tuple_key!(A, B, C, D, E);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_sorted<K>(keys: &[K])
+    where
+        K: Key + std::fmt::Debug + PartialEq,
+    {
+        let encoded: Vec<Vec<u8>> = keys.iter().map(encode).collect();
+
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(encoded, sorted);
+
+        for (key, encoded) in keys.iter().zip(&encoded) {
+            assert_eq!(&decode::<K>(encoded).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn strings() {
+        assert_sorted(&[
+            "".to_string(),
+            "a".to_string(),
+            "ab".to_string(),
+            "b".to_string(),
+        ]);
+        assert_eq!(encode(&"abc".to_string()), b"abc");
+    }
+
+    #[test]
+    fn integers() {
+        assert_sorted(&[0u32, 1, 255, 256, u32::MAX]);
+        assert_sorted(&[i64::MIN, -256, -1, 0, 1, 256, i64::MAX]);
+        assert_sorted(&[i8::MIN, -1, 0, 1, i8::MAX]);
+    }
+
+    #[test]
+    fn tuples() {
+        assert_sorted(&[
+            ("a".to_string(), 2u32),
+            ("a".to_string(), 10u32),
+            ("a\0".to_string(), 0u32),
+            ("ab".to_string(), 0u32),
+            ("b".to_string(), 0u32),
+        ]);
+        assert_sorted(&[
+            (-1i32, vec![0u8, 0], "z".to_string()),
+            (-1i32, vec![0u8, 1], "a".to_string()),
+            (0i32, vec![], "a".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn integers_after_bytes() {
+        assert_sorted(&[("a".to_string(), 0u8), ("a".to_string(), u8::MAX)]);
+        assert_sorted(&[("a".to_string(), 0xff00u16)]);
+        assert_sorted(&[(vec![0u8], 0u64), (vec![0u8], u64::MAX)]);
+        assert_sorted(&[(vec![], u64::MAX), (vec![0xffu8], u64::MAX)]);
+    }
+
+    #[test]
+    fn same_as_tuples() {
+        let keys = [
+            ("a".to_string(), -1i64),
+            ("a".to_string(), 1),
+            ("a".to_string(), 256),
+            ("b".to_string(), 0),
+        ];
+        let tuples = [
+            Tuple::from(("a", -1)),
+            Tuple::from(("a", 1)),
+            Tuple::from(("a", 256)),
+            Tuple::from(("b", 0)),
+        ];
+
+        assert_sorted(&keys);
+        assert_eq!(
+            keys.iter().map(encode).collect::<Vec<_>>(),
+            tuples.iter().map(Tuple::pack).collect::<Vec<_>>()
+        );
+        assert_eq!(encode(&7u8), Tuple::from((7,)).pack());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(decode::<u32>(&[0, 1]), Err(Error::KeyDecoding)));
+        assert!(matches!(decode::<u8>(&[0, 1]), Err(Error::KeyDecoding)));
+        assert!(matches!(
+            decode::<(String, u8)>(b"no terminator"),
+            Err(Error::KeyDecoding)
+        ));
+        assert!(matches!(
+            decode::<u8>(&encode(&256u32)),
+            Err(Error::KeyDecoding)
+        ));
+        assert!(matches!(
+            decode::<(String, u8)>(&encode(&("a".to_string(), 1u8, 2u8))),
+            Err(Error::KeyDecoding)
+        ));
+    }
+}
@@ -1,23 +1,86 @@
+use begin_immediate::SqliteConnectionExt;
+use chrono::{DateTime, Datelike, Utc};
+use futures::{Stream, StreamExt, TryStreamExt};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use sqlx::Connection;
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use storage::append::Append;
 use storage::update_in_place::UpdateInPlace;
 use storage::Storage;
 use thiserror::Error;
+use watch::Pending;
 
+mod backup;
 mod begin_immediate;
+mod changes;
+mod codec;
+mod index;
+mod key;
+mod maintenance;
+mod read_only;
+mod retention;
+mod schema;
 mod storage;
+mod transaction;
+mod tuple;
+mod typed;
+mod watch;
+
+pub use backup::BackupProgress;
+pub use changes::Change;
+pub use codec::Codec;
+pub use key::Key;
+pub use maintenance::{Maintenance, MaintenanceHandle, MaintenanceReport};
+pub use read_only::ReadOnlyDb;
+pub use retention::{GarbageCollected, Retention};
+pub use schema::Strategy;
+pub use transaction::Transaction;
+pub use tuple::{Element, Tuple};
+pub use typed::TypedDb;
+pub use watch::{Event, EventKind};
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("error with sqlx")]
     SqlxError(#[from] sqlx::Error),
     #[error("could not serialize")]
-    Serialization(#[from] ciborium::ser::Error<std::io::Error>),
+    Serialization(#[source] Box<dyn std::error::Error + Send + Sync>),
     #[error("could not deserialize")]
-    Deserialization(#[from] ciborium::de::Error<std::io::Error>),
+    Deserialization(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("the condition for a conditional write did not hold")]
+    ConditionFailed,
+    #[error("could not decode a key")]
+    KeyDecoding,
+    #[error("no index named {0} is registered")]
+    UnknownIndex(String),
+    #[error("a watcher fell behind and missed {0} events")]
+    Lagged(u64),
+    #[error("the database does not have a change log")]
+    NoChangeLog,
+    #[error("the database's format version {0} is newer than this version of kvqlite supports")]
+    UnsupportedFormatVersion(u32),
+    #[error(
+        "the database's format version {0} is old, and it must be opened read-write to migrate it"
+    )]
+    MigrationNeeded(u32),
+    #[error("the database uses the {0} storage strategy")]
+    StrategyMismatch(Strategy),
+    #[error("the database has key/values stored with both storage strategies")]
+    AmbiguousStrategy,
+    #[error("the database's values are serialized with {0:?}")]
+    CodecMismatch(Codec),
+    #[error("{0:?} values can't be read without knowing their type")]
+    UnsupportedCodec(Codec),
+    #[error("the maintenance task has stopped")]
+    MaintenanceStopped,
+    #[error("the backup can't be restored: {0}")]
+    InvalidBackup(String),
+    #[error("could not read or write a file")]
+    Io(#[from] std::io::Error),
 }
 
 #[derive(Clone, Debug)]
@@ -26,6 +89,8 @@ where
     T: Storage,
 {
     storage: T,
+    /// keeps the maintenance task running, if there is one
+    maintenance: Option<maintenance::Task>,
 }
 
 impl<T> Db<T>
@@ -51,7 +116,54 @@ where
         K: AsRef<[u8]> + ?Sized,
         V: Serialize + ?Sized,
     {
-        self.storage.write(key, value).await
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let value = self.storage.codec().serialize(value)?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        let mut pending = Pending::default();
+
+        pending
+            .write(&self.storage, &mut tx, key, value, None)
+            .await?;
+
+        tx.commit().await?;
+
+        pending.publish(&self.storage);
+
+        Ok(())
+    }
+
+    /// write a key/value that reads as missing once `expiry` passes.
+    /// writing the key again without an expiry clears its expiry.
+    pub async fn write_with_expiry<K, V>(
+        &self,
+        key: &K,
+        value: &V,
+        expiry: Expiry,
+    ) -> Result<(), Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let value = self.storage.codec().serialize(value)?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        let mut pending = Pending::default();
+
+        pending
+            .write(&self.storage, &mut tx, key, value, expiry.expires_at())
+            .await?;
+
+        tx.commit().await?;
+
+        pending.publish(&self.storage);
+
+        Ok(())
     }
 
     /// read a value
@@ -60,7 +172,9 @@ where
         K: AsRef<[u8]> + ?Sized,
         V: DeserializeOwned,
     {
-        self.storage.read(key).await
+        let mut conn = self.storage.pool().acquire().await?;
+
+        self.storage.read(&mut conn, key).await
     }
 
     /// delete a key/value
@@ -68,47 +182,683 @@ where
     where
         K: AsRef<[u8]> + ?Sized,
     {
-        self.storage.delete(key).await
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        let mut pending = Pending::default();
+
+        pending.delete(&self.storage, &mut tx, key).await?;
+
+        tx.commit().await?;
+
+        pending.publish(&self.storage);
+
+        Ok(())
+    }
+
+    /// write many key/values in a single transaction
+    pub async fn write_many<K, V, I>(&self, entries: I) -> Result<(), Error>
+    where
+        K: AsRef<[u8]>,
+        V: Serialize,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        let mut pending = Pending::default();
+
+        for (key, value) in entries {
+            let value = self.storage.codec().serialize(&value)?;
+            pending
+                .write(&self.storage, &mut tx, key.as_ref(), value, None)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        pending.publish(&self.storage);
+
+        Ok(())
+    }
+
+    /// read many values from a single snapshot of the database,
+    /// returned in the same order as `keys`
+    pub async fn read_many<K, V, I>(&self, keys: I) -> Result<Vec<Option<V>>, Error>
+    where
+        K: AsRef<[u8]>,
+        V: DeserializeOwned,
+        I: IntoIterator<Item = K>,
+    {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let mut tx = conn.begin().await?;
+
+        let mut values = vec![];
+
+        for key in keys {
+            values.push(self.storage.read(&mut tx, key.as_ref()).await?);
+        }
+
+        tx.commit().await?;
+
+        Ok(values)
+    }
+
+    /// delete many keys in a single transaction
+    pub async fn delete_many<K, I>(&self, keys: I) -> Result<(), Error>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        let mut pending = Pending::default();
+
+        for key in keys {
+            pending.delete(&self.storage, &mut tx, key.as_ref()).await?;
+        }
+
+        tx.commit().await?;
+
+        pending.publish(&self.storage);
+
+        Ok(())
+    }
+
+    /// read a value and its current revision
+    pub async fn read_with_revision<K, V>(&self, key: &K) -> Result<Option<(V, Revision)>, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+    {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        if let Some((value_bytes, revision)) = self.storage.current(&mut conn, key).await? {
+            let value: V = self.storage.codec().deserialize(&value_bytes)?;
+            Ok(Some((value, revision)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// write a key/value only if the current value equals `expected`,
+    /// where `None` means the key must not exist.
+    /// values are compared by their serialized bytes.
+    pub async fn compare_and_swap<K, V>(
+        &self,
+        key: &K,
+        expected: Option<&V>,
+        value: &V,
+    ) -> Result<Revision, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        let expected = if let Some(expected) = expected {
+            Some(self.storage.codec().serialize(expected)?)
+        } else {
+            None
+        };
+
+        self.write_if(key, value, |current| {
+            current.map(|(value_bytes, _)| value_bytes)
+                == expected.as_ref().map(|expected| expected.as_bytes())
+        })
+        .await
+    }
+
+    /// write a key/value only if the key's current revision is `revision`
+    pub async fn write_if_revision<K, V>(
+        &self,
+        key: &K,
+        revision: Revision,
+        value: &V,
+    ) -> Result<Revision, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        self.write_if(key, value, |current| {
+            current.map(|(_, current_revision)| current_revision) == Some(revision)
+        })
+        .await
+    }
+
+    /// write a key/value only if the key does not exist
+    pub async fn insert_if_absent<K, V>(&self, key: &K, value: &V) -> Result<Revision, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        self.write_if(key, value, |current| current.is_none()).await
+    }
+
+    /// write a key/value only if the key exists
+    pub async fn update_if_present<K, V>(&self, key: &K, value: &V) -> Result<Revision, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        self.write_if(key, value, |current| current.is_some()).await
+    }
+
+    /// write a key/value if `condition` holds for the key's
+    /// current serialized value and revision
+    async fn write_if<K, V, F>(&self, key: &K, value: &V, condition: F) -> Result<Revision, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: Serialize + ?Sized,
+        F: FnOnce(Option<(&[u8], Revision)>) -> bool,
+    {
+        let value = self.storage.codec().serialize(value)?;
+
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        let current = self.storage.current(&mut tx, key).await?;
+
+        if !condition(
+            current
+                .as_ref()
+                .map(|(value_bytes, revision)| (&value_bytes[..], *revision)),
+        ) {
+            tx.rollback().await?;
+            return Err(Error::ConditionFailed);
+        }
+
+        let mut pending = Pending::default();
+
+        let revision = pending
+            .write(&self.storage, &mut tx, key, value, None)
+            .await?;
+
+        tx.commit().await?;
+
+        pending.publish(&self.storage);
+
+        Ok(revision)
+    }
+
+    /// read the key/values with keys in the given range, in key order
+    pub async fn range<K, V, R>(&self, range: R) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        K: AsRef<[u8]>,
+        V: DeserializeOwned,
+        R: RangeBounds<K>,
+    {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let start = range.start_bound().map(|k| k.as_ref());
+        let end = range.end_bound().map(|k| k.as_ref());
+        self.storage.range(&mut conn, start, end, None).await
+    }
+
+    /// read the key/values with keys starting with `prefix`, in key order
+    pub async fn prefix<P, V>(&self, prefix: &P) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        P: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+    {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let prefix = prefix.as_ref();
+        let end = storage::prefix_end(prefix);
+        self.storage
+            .range(&mut conn, Bound::Included(prefix), bound_as_ref(&end), None)
+            .await
+    }
+
+    /// stream the current keys in key order.
+    /// keys are read a page at a time, so no read transaction
+    /// is held open between pages.
+    pub fn stream_keys(&self) -> impl Stream<Item = Result<Vec<u8>, Error>> + '_ {
+        futures::stream::try_unfold(Some(Bound::Unbounded), move |after| async move {
+            let Some(after) = after else {
+                return Ok::<_, Error>(None);
+            };
+
+            let mut conn = self.storage.pool().acquire().await?;
+
+            let keys = self
+                .storage
+                .range_keys(
+                    &mut conn,
+                    bound_as_ref(&after),
+                    Bound::Unbounded,
+                    Some(STREAM_PAGE_SIZE),
+                )
+                .await?;
+
+            let next = if keys.len() < STREAM_PAGE_SIZE as usize {
+                None
+            } else {
+                keys.last().cloned().map(Bound::Excluded)
+            };
+
+            Ok(Some((keys, next)))
+        })
+        .map_ok(|keys| futures::stream::iter(keys.into_iter().map(Ok)))
+        .try_flatten()
+    }
+
+    /// stream the current key/values in key order.
+    /// entries are read a page at a time, so no read transaction
+    /// is held open between pages.
+    pub fn stream_entries<V>(&self) -> impl Stream<Item = Result<(Vec<u8>, V), Error>> + '_
+    where
+        V: DeserializeOwned,
+    {
+        futures::stream::try_unfold(Some(Bound::Unbounded), move |after| async move {
+            let Some(after) = after else {
+                return Ok::<_, Error>(None);
+            };
+
+            let mut conn = self.storage.pool().acquire().await?;
+
+            let entries: Vec<(Vec<u8>, V)> = self
+                .storage
+                .range(
+                    &mut conn,
+                    bound_as_ref(&after),
+                    Bound::Unbounded,
+                    Some(STREAM_PAGE_SIZE),
+                )
+                .await?;
+
+            let next = if entries.len() < STREAM_PAGE_SIZE as usize {
+                None
+            } else {
+                entries.last().map(|(key, _)| Bound::Excluded(key.clone()))
+            };
+
+            Ok(Some((entries, next)))
+        })
+        .map_ok(|entries| futures::stream::iter(entries.into_iter().map(Ok)))
+        .try_flatten()
+    }
+
+    /// watch a key, streaming an event each time a write or delete of it commits.
+    /// only changes committed after this call are seen.
+    /// a watcher that falls too far behind gets `Error::Lagged` and then
+    /// continues with later events.
+    pub fn watch<K>(&self, key: &K) -> impl Stream<Item = Result<Event, Error>>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        watch::watch_key(&self.storage, key.as_ref())
+    }
+
+    /// watch every key starting with `prefix`, like `watch`
+    pub fn watch_prefix<P>(&self, prefix: &P) -> impl Stream<Item = Result<Event, Error>>
+    where
+        P: AsRef<[u8]> + ?Sized,
+    {
+        watch::watch_prefix(&self.storage, prefix.as_ref())
+    }
+
+    /// a handle to the keys in the bucket named `name`.
+    /// each bucket has its own keys, independent of the keys in other
+    /// buckets, and every operation on the handle applies only to its bucket.
+    /// the handle returned by `Builder::finish` is for the default bucket,
+    /// named "".
+    pub fn bucket(&self, name: &str) -> Db<T> {
+        Db {
+            storage: self.storage.with_bucket(name),
+            maintenance: self.maintenance.clone(),
+        }
+    }
+
+    /// a handle to the maintenance task started by `Builder::maintenance`,
+    /// or `None` if there isn't one
+    pub fn maintenance(&self) -> Option<MaintenanceHandle> {
+        self.maintenance.as_ref().map(maintenance::Task::handle)
+    }
+
+    /// a handle to this database's bucket whose keys are all `K`
+    /// and whose values are all `V`
+    pub fn typed<K, V>(&self) -> TypedDb<K, V, T>
+    where
+        K: Key,
+        V: Serialize + DeserializeOwned,
+    {
+        TypedDb::new(self.clone())
+    }
+
+    /// register a secondary index named `name` on this bucket, indexing each
+    /// value by the key `extract` returns for it.
+    /// values that `extract` returns `None` for, or that are not a `V`,
+    /// are not indexed.
+    ///
+    /// indexes are kept up to date by every write and delete in the same
+    /// transaction. registering an index builds it from the current values.
+    /// the closure only lives in this process, so it must be registered each
+    /// time the database is opened, and writes by handles that haven't
+    /// registered it, such as those of other processes, don't update it.
+    /// reading the index skips keys whose values no longer match,
+    /// but keys written by those handles are missing until it is registered
+    /// again. `create_path_index` doesn't have these limits.
+    pub async fn create_index<K, V, F>(&self, name: &str, extract: F) -> Result<(), Error>
+    where
+        K: Key,
+        V: DeserializeOwned,
+        F: Fn(&V) -> Option<K> + Send + Sync + 'static,
+    {
+        let codec = self.storage.codec();
+
+        self.register_index(
+            name,
+            index::Definition::Closure(Arc::new(move |value_bytes| {
+                let value: V = codec.deserialize(value_bytes).ok()?;
+                extract(&value).map(|key| key::encode(&key))
+            })),
+        )
+        .await
+    }
+
+    /// register a secondary index named `name` on this bucket, indexing
+    /// each value by the field at `path`, like `create_index`.
+    /// `path` is a `.` separated list of field names and array positions,
+    /// like `address.city`. fields that are strings, numbers, booleans or
+    /// null are indexed as an `Element`, so query the index with `Element`s.
+    /// whole numbers are indexed as `Element::Int` however they were
+    /// serialized, and other numbers as `Element::Double`.
+    ///
+    /// path indexes are stored in the database, so every handle to it,
+    /// in any process, keeps them up to date, and they stay registered
+    /// until they are dropped.
+    /// they need a self-describing codec, so with `Codec::Postcard` and
+    /// `Codec::Raw` they fail with `Error::UnsupportedCodec`.
+    pub async fn create_path_index(&self, name: &str, path: &str) -> Result<(), Error> {
+        if !self.storage.codec().is_self_describing() {
+            return Err(Error::UnsupportedCodec(self.storage.codec()));
+        }
+
+        self.register_index(name, index::Definition::Path(path.to_string()))
+            .await
+    }
+
+    async fn register_index(&self, name: &str, definition: index::Definition) -> Result<(), Error> {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        // the closure this replaces, if any, which is registered again
+        // if the new index can't be built
+        let previous = self.storage.indexes().get(self.storage.bucket(), name);
+
+        // registering while holding the write lock means every write
+        // committed after the rebuild sees the index
+        let registered = index::register(&self.storage, &mut tx, name, definition).await;
+
+        if let Err(e) = registered {
+            match previous {
+                Some(extract) => {
+                    self.storage
+                        .indexes()
+                        .insert(self.storage.bucket(), name, extract)
+                }
+                None => {
+                    self.storage.indexes().remove(self.storage.bucket(), name);
+                }
+            }
+            return Err(e);
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// unregister the index named `name` on this bucket and delete its entries
+    pub async fn drop_index(&self, name: &str) -> Result<(), Error> {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        index::unregister(&self.storage, &mut tx, name).await?;
+
+        index::clear(&mut tx, self.storage.bucket(), Some(name)).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// read the key/values whose value is indexed as `value` in the index
+    /// named `name`, in key order
+    pub async fn index_get<K, V>(&self, name: &str, value: &K) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        K: Key,
+        V: DeserializeOwned,
+    {
+        self.index_range::<K, V, _>(name, (Bound::Included(value), Bound::Included(value)))
+            .await
+    }
+
+    /// read the key/values whose values are indexed in the given range in
+    /// the index named `name`, in index order
+    pub async fn index_range<K, V, R>(
+        &self,
+        name: &str,
+        range: R,
+    ) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        K: Key,
+        V: DeserializeOwned,
+        R: RangeBounds<K>,
+    {
+        let start = range.start_bound().map(key::encode);
+        let end = range.end_bound().map(key::encode);
+
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let mut tx = conn.begin().await?;
+
+        let Some(extract) = index::extractor(&self.storage, &mut tx, name).await? else {
+            return Err(Error::UnknownIndex(name.to_string()));
+        };
+
+        let keys = index::lookup(
+            &mut tx,
+            self.storage.bucket(),
+            name,
+            bound_as_ref(&start),
+            bound_as_ref(&end),
+        )
+        .await?;
+
+        let mut entries = vec![];
+
+        for key in keys {
+            // expired keys stay indexed until they are purged, and handles
+            // without a closure index don't update its entries, so each
+            // value is checked against the index again
+            let Some((value_bytes, _)) = self.storage.current(&mut tx, &key).await? else {
+                continue;
+            };
+
+            let matches = extract(&value_bytes).is_some_and(|value| {
+                RangeBounds::<Vec<u8>>::contains(&(start.as_ref(), end.as_ref()), &value)
+            });
+
+            if matches {
+                entries.push((key, self.storage.codec().deserialize(&value_bytes)?));
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(entries)
+    }
+
+    /// delete the bucket named `name` and all of its keys in a single transaction
+    pub async fn drop_bucket(&self, name: &str) -> Result<(), Error> {
+        let bucket = self.storage.with_bucket(name);
+
+        let mut conn = bucket.pool().acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        let mut pending = Pending::default();
+
+        pending.clear(&bucket, &mut tx).await?;
+
+        tx.commit().await?;
+
+        pending.publish(&bucket);
+
+        Ok(())
+    }
+
+    /// read up to `limit` changes from the change log with sequence numbers
+    /// greater than `after`, oldest first.
+    /// the change log covers every bucket.
+    /// start from 0 to read the whole log.
+    pub async fn changes(&self, after: u64, limit: u32) -> Result<Vec<Change>, Error> {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        if !changes::exists(&mut conn).await? {
+            return Err(Error::NoChangeLog);
+        }
+
+        changes::after(&mut conn, self.storage.codec(), after, limit).await
+    }
+
+    /// stream the changes with sequence numbers greater than `after`,
+    /// including changes committed by other processes, checking for new
+    /// changes every `poll_interval` once caught up. the stream does not end.
+    /// to resume after a restart, pass the sequence number of the last
+    /// change handled.
+    pub fn tail_changes(
+        &self,
+        after: u64,
+        poll_interval: std::time::Duration,
+    ) -> impl Stream<Item = Result<Change, Error>> + '_ {
+        futures::stream::try_unfold(after, move |after| async move {
+            let changes = self.changes(after, STREAM_PAGE_SIZE).await?;
+
+            let next = if let Some(last) = changes.last() {
+                last.seq()
+            } else {
+                tokio::time::sleep(poll_interval).await;
+                after
+            };
+
+            Ok::<_, Error>(Some((changes, next)))
+        })
+        .map_ok(|changes| futures::stream::iter(changes.into_iter().map(Ok)))
+        .try_flatten()
+    }
+
+    /// delete changes with sequence numbers up to and including `through`
+    /// from the change log, returning how many were deleted.
+    /// sequence numbers are never reused.
+    pub async fn truncate_changes(&self, through: u64) -> Result<u64, Error> {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        if !changes::exists(&mut tx).await? {
+            return Err(Error::NoChangeLog);
+        }
+
+        let truncated = changes::truncate(&mut tx, through).await?;
+
+        tx.commit().await?;
+
+        Ok(truncated)
+    }
+
+    /// begin a transaction.
+    /// the transaction holds the database's write lock until it is
+    /// committed or rolled back, and is rolled back if it is dropped.
+    pub async fn transaction(&self) -> Result<Transaction<'_, T>, Error> {
+        let conn = self.storage.pool().acquire().await?;
+
+        Transaction::begin(&self.storage, conn).await
+    }
+
+    /// delete expired keys, returning how many were deleted.
+    /// expired keys read as missing whether or not they have been purged.
+    pub async fn purge_expired(&self) -> Result<u64, Error> {
+        let mut conn = self.storage.pool().acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        let purged = self.storage.purge_expired(&mut tx).await?;
+
+        tx.commit().await?;
+
+        Ok(purged)
     }
 
     /// get the current keys
     pub async fn keys(&self) -> Result<Vec<Vec<u8>>, Error> {
-        self.storage.keys().await
+        let mut conn = self.storage.pool().acquire().await?;
+
+        self.storage.keys(&mut conn).await
     }
 
     /// get the current number of keys
     pub async fn keys_count(&self) -> Result<u64, Error> {
-        self.storage.keys_count().await
+        let mut conn = self.storage.pool().acquire().await?;
+
+        self.storage.keys_count(&mut conn).await
+    }
+
+    /// write a copy of the whole database, every bucket included,
+    /// to a new file at `path`.
+    /// the copy is a consistent snapshot, and other handles can keep writing
+    /// while it is made. in-memory databases can be backed up too.
+    pub async fn backup_to(&self, path: &Path) -> Result<(), Error> {
+        self.backup_to_with_progress(path, |_| {}).await
+    }
+
+    /// back up the database like `backup_to`, calling `progress`
+    /// every so often while pages are copied, and once more when done.
+    /// the pages copied are estimated from the size of the backup file
+    /// so far, so they are only exact in the last call.
+    pub async fn backup_to_with_progress<F>(&self, path: &Path, progress: F) -> Result<(), Error>
+    where
+        F: FnMut(BackupProgress),
+    {
+        backup::backup(self.storage.pool(), path, progress).await
+    }
+
+    /// replace the database file at `path` with the backup at `backup`.
+    /// the backup is checked first: it must be intact, have this storage
+    /// strategy's tables, and not be from a newer format version.
+    /// backups from older format versions are migrated when the database
+    /// is next opened.
+    /// nothing may have the database at `path` open while it is restored.
+    pub async fn restore(backup: &Path, path: &Path) -> Result<(), Error> {
+        backup::restore(backup, path, T::STRATEGY).await
     }
 }
 
 impl Db<Append> {
     /// keep only the latest entry for each key,
-    /// deleting values that are not the latest value
-    pub async fn collect_garbage(&self) -> Result<(), Error> {
-        let mut conn = self.storage.pool.acquire().await?;
-
-        sqlx::query(
-            "
-            with current_values as (
-                select
-                    id,
-                    max(inserted_at)
-                from vvalues
-                group by key_id
-            )
-            delete from vvalues
-            where id not in (
-                select
-                    id
-                from current_values
-            )
-        ",
-        )
-        .execute(&mut *conn)
-        .await?;
+    /// deleting values that are not the latest value.
+    /// keys whose latest entry is a tombstone are kept whole until the
+    /// tombstone is older than the tombstone retention, then removed entirely.
+    pub async fn collect_garbage(&self) -> Result<GarbageCollected, Error> {
+        self.collect_garbage_with(Retention::latest()).await
+    }
 
-        Ok(())
+    /// delete the values that `retention` doesn't keep, like `collect_garbage`.
+    /// keys are collected a batch at a time, each in its own transaction,
+    /// so writers only wait for one batch.
+    pub async fn collect_garbage_with(
+        &self,
+        retention: Retention,
+    ) -> Result<GarbageCollected, Error> {
+        self.storage.collect_garbage(retention).await
     }
 
     /// the total number of entries, including duplicates and deletes
@@ -120,17 +870,316 @@ impl Db<Append> {
             select
                 count(*)
             from vvalues
+            inner join keys
+                on keys.id = vvalues.key_id
+            where keys.bucket = ?
             ",
         )
+        .bind(&self.storage.bucket)
         .fetch_one(&mut *conn)
         .await?;
 
         Ok(entries_count)
     }
 
-    // TODO
-    // pub fn read_as_of()
-    // pub fn read_range()
+    /// read the latest value of a key at or before the given time
+    pub async fn read_as_of<K, V>(&self, key: &K, as_of: DateTime<Utc>) -> Result<AsOf<V>, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+    {
+        let mut conn = self.storage.pool.acquire().await?;
+
+        let value_bytes: Option<(Option<Vec<u8>>,)> = sqlx::query_as(
+            "
+        select
+            vvalues.value
+        from keys
+        inner join vvalues
+            on vvalues.key_id = keys.id
+        where bucket = ?
+        and key = ?
+        and vvalues.inserted_at <= ?
+        order by vvalues.id desc
+        limit 1
+        ",
+        )
+        .bind(&self.storage.bucket)
+        .bind(key.as_ref())
+        .bind(sqlite_timestamp(as_of))
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        match value_bytes {
+            Some((Some(value_bytes),)) => {
+                let value: V = self.storage.codec.deserialize(&value_bytes)?;
+                Ok(AsOf::Value(value))
+            }
+            Some((None,)) => Ok(AsOf::Deleted),
+            None => Ok(AsOf::Absent),
+        }
+    }
+
+    /// delete a key by appending a tombstone,
+    /// keeping its earlier values visible to `read_as_of`.
+    /// does nothing if the key does not exist.
+    pub async fn delete_keeping_history<K>(&self, key: &K) -> Result<(), Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        let mut conn = self.storage.pool.acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        let mut pending = Pending::default();
+
+        let old_value = pending.old_value(&self.storage, &mut tx, key).await?;
+
+        self.storage.append_tombstone(&mut tx, key).await?;
+
+        index::remove(&mut tx, &self.storage.bucket, key.as_ref()).await?;
+
+        pending
+            .deleted(&self.storage, &mut tx, key, old_value)
+            .await?;
+
+        tx.commit().await?;
+
+        pending.publish(&self.storage);
+
+        Ok(())
+    }
+
+    /// stream every version of a key inserted within the given time range,
+    /// including tombstones
+    pub fn read_range<K, V, R>(
+        &self,
+        key: &K,
+        range: R,
+        order: Order,
+    ) -> impl Stream<Item = Result<Version<V>, Error>> + '_
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+        R: RangeBounds<DateTime<Utc>>,
+    {
+        let (start_included, start_excluded) = match range.start_bound() {
+            Bound::Included(t) => (Some(sqlite_timestamp(*t)), None),
+            Bound::Excluded(t) => (None, Some(sqlite_timestamp(*t))),
+            Bound::Unbounded => (None, None),
+        };
+
+        let (end_included, end_excluded) = match range.end_bound() {
+            Bound::Included(t) => (Some(sqlite_timestamp(*t)), None),
+            Bound::Excluded(t) => (None, Some(sqlite_timestamp(*t))),
+            Bound::Unbounded => (None, None),
+        };
+
+        sqlx::query_as(
+            "
+            select
+                vvalues.value,
+                vvalues.inserted_at
+            from keys
+            inner join vvalues
+                on vvalues.key_id = keys.id
+            where bucket = ?6
+            and key = ?1
+            and (?2 is null or vvalues.inserted_at >= ?2)
+            and (?3 is null or vvalues.inserted_at > ?3)
+            and (?4 is null or vvalues.inserted_at <= ?4)
+            and (?5 is null or vvalues.inserted_at < ?5)
+            -- ?7 is whether to order oldest first
+            order by
+                case when ?7 then vvalues.id end asc,
+                vvalues.id desc
+            ",
+        )
+        .bind(key.as_ref().to_vec())
+        .bind(start_included)
+        .bind(start_excluded)
+        .bind(end_included)
+        .bind(end_excluded)
+        .bind(self.storage.bucket.clone())
+        .bind(order == Order::Ascending)
+        .fetch(&self.storage.pool)
+        .map(|row| {
+            let (value_bytes, inserted_at): (Option<Vec<u8>>, DateTime<Utc>) = row?;
+
+            let value = if let Some(value_bytes) = value_bytes {
+                Some(self.storage.codec.deserialize(&value_bytes)?)
+            } else {
+                None
+            };
+
+            Ok(Version { value, inserted_at })
+        })
+    }
+
+    /// convert the database to `UpdateInPlace` in a single transaction,
+    /// keeping only the latest value of each key in every bucket
+    /// and discarding the rest of their history.
+    /// other handles to the database must not be used afterwards.
+    /// a maintenance task is restarted with the same settings for the
+    /// converted database.
+    pub async fn into_update_in_place(mut self) -> Result<Db<UpdateInPlace>, Error> {
+        // the task maintaining the old storage stops,
+        // and one with the same settings maintains the new one
+        let maintenance = self.maintenance.take().map(|task| task.settings());
+
+        let mut conn = self.storage.pool.acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        schema::append_to_update_in_place(&mut tx).await?;
+
+        tx.commit().await?;
+
+        let Append {
+            pool,
+            codec,
+            events,
+            bucket,
+            indexes,
+            ..
+        } = self.storage;
+
+        let storage = UpdateInPlace {
+            pool,
+            codec,
+            events,
+            bucket,
+            indexes,
+        };
+
+        let maintenance =
+            maintenance.map(|maintenance| maintenance::Task::start(&storage, maintenance));
+
+        Ok(Db {
+            storage,
+            maintenance,
+        })
+    }
+}
+
+impl Db<UpdateInPlace> {
+    /// convert the database to `Append` in a single transaction,
+    /// with the current value of each key in every bucket as its only version.
+    /// other handles to the database must not be used afterwards.
+    /// a maintenance task is restarted with the same settings for the
+    /// converted database.
+    pub async fn into_append(mut self) -> Result<Db<Append>, Error> {
+        // the task maintaining the old storage stops,
+        // and one with the same settings maintains the new one
+        let maintenance = self.maintenance.take().map(|task| task.settings());
+
+        let mut conn = self.storage.pool.acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        schema::update_in_place_to_append(&mut tx).await?;
+
+        tx.commit().await?;
+
+        let UpdateInPlace {
+            pool,
+            codec,
+            events,
+            bucket,
+            indexes,
+        } = self.storage;
+
+        let storage = Append {
+            pool,
+            codec,
+            events,
+            bucket,
+            indexes,
+            tombstones: false,
+            tombstone_retention: std::time::Duration::ZERO,
+        };
+
+        let maintenance =
+            maintenance.map(|maintenance| maintenance::Task::start(&storage, maintenance));
+
+        Ok(Db {
+            storage,
+            maintenance,
+        })
+    }
+}
+
+/// when a key expires
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expiry {
+    /// expire after this much time has passed
+    After(std::time::Duration),
+    /// expire at this time
+    At(DateTime<Utc>),
+}
+
+impl Expiry {
+    /// the time the key expires at,
+    /// or `None` if that is too far in the future to store
+    fn expires_at(self) -> Option<DateTime<Utc>> {
+        let expires_at = match self {
+            Expiry::After(duration) => chrono::TimeDelta::from_std(duration)
+                .ok()
+                .and_then(|duration| Utc::now().checked_add_signed(duration))?,
+            Expiry::At(expires_at) => expires_at,
+        };
+
+        // timestamps are compared as strings, which only sorts
+        // correctly for four digit years
+        if expires_at.year() > 9999 {
+            None
+        } else {
+            Some(expires_at)
+        }
+    }
+}
+
+/// identifies the value of a key at the time it was read,
+/// for use with `Db::write_if_revision`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Revision(pub(crate) i64);
+
+/// the order to return versions in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    /// oldest first
+    Ascending,
+    /// newest first
+    Descending,
+}
+
+/// a value of a key as it was written at a point in time
+#[derive(Debug, PartialEq, Eq)]
+pub struct Version<V> {
+    /// the value, or `None` if this version is a tombstone
+    pub value: Option<V>,
+    pub inserted_at: DateTime<Utc>,
+}
+
+/// the state of a key at a point in time
+#[derive(Debug, PartialEq, Eq)]
+pub enum AsOf<V> {
+    /// the key had this value
+    Value(V),
+    /// the key had been deleted
+    Deleted,
+    /// there is no record of the key
+    Absent,
+}
+
+impl<V> AsOf<V> {
+    /// the value, if the key had one
+    pub fn value(self) -> Option<V> {
+        match self {
+            AsOf::Value(value) => Some(value),
+            AsOf::Deleted | AsOf::Absent => None,
+        }
+    }
 }
 
 pub struct Builder<T> {
@@ -143,8 +1192,27 @@ impl<T> Builder<T> {
     where
         T: Storage,
     {
-        let storage = T::open(self.options).await?;
-        Ok(Db { storage })
+        let mut options = self.options;
+        let maintenance = options.maintenance.take().filter(|_| !options.read_only);
+
+        let storage = T::open(options).await?;
+
+        let maintenance =
+            maintenance.map(|maintenance| maintenance::Task::start(&storage, maintenance));
+
+        Ok(Db {
+            storage,
+            maintenance,
+        })
+    }
+
+    /// open an existing database read-only, returning a handle that can't
+    /// write. the database file is opened read-only and no tables are created,
+    /// so many processes can safely read a file another process writes.
+    /// call this after setting any other options.
+    pub fn read_only(mut self) -> ReadOnlyBuilder<T> {
+        self.options.read_only = true;
+        ReadOnlyBuilder { builder: self }
     }
 
     pub fn in_memory(mut self) -> Self {
@@ -156,12 +1224,172 @@ impl<T> Builder<T> {
         self.options.db_path = Some(path.to_path_buf());
         self
     }
+
+    /// record every committed write and delete in a durable change log,
+    /// readable with `Db::changes` and `Db::tail_changes`.
+    /// once a database has a change log, every handle that opens it
+    /// afterwards records changes, whether or not it enables this.
+    pub fn change_log(mut self) -> Self {
+        self.options.change_log = true;
+        self
+    }
+
+    /// the fewest connections the pool keeps open
+    pub fn min_connections(mut self, min_connections: u32) -> Self {
+        self.options.min_connections = Some(min_connections);
+        self
+    }
+
+    /// the most connections the pool opens, 10 by default
+    pub fn max_connections(mut self, max_connections: u32) -> Self {
+        self.options.max_connections = Some(max_connections);
+        self
+    }
+
+    /// how long to wait for another connection's lock before failing,
+    /// 5 seconds by default
+    pub fn busy_timeout(mut self, busy_timeout: std::time::Duration) -> Self {
+        self.options.busy_timeout = Some(busy_timeout);
+        self
+    }
+
+    /// the journal mode, `Wal` by default for databases on disk
+    pub fn journal_mode(mut self, journal_mode: JournalMode) -> Self {
+        self.options.journal_mode = Some(journal_mode);
+        self
+    }
+
+    /// the `synchronous` level, sqlite's `Full` by default,
+    /// trading durability on power loss for write speed
+    pub fn synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.options.synchronous = Some(synchronous);
+        self
+    }
+
+    /// the `cache_size` of each connection: a number of pages if positive,
+    /// or a number of KiB if negative
+    pub fn page_cache_size(mut self, page_cache_size: i64) -> Self {
+        self.options.page_cache_size = Some(page_cache_size);
+        self
+    }
+
+    /// the `mmap_size` of each connection in bytes, where 0 disables
+    /// memory-mapped I/O
+    pub fn mmap_size(mut self, mmap_size: u64) -> Self {
+        self.options.mmap_size = Some(mmap_size);
+        self
+    }
+
+    /// set another PRAGMA on each connection as it is opened
+    pub fn pragma(mut self, key: &str, value: &str) -> Self {
+        self.options
+            .pragmas
+            .push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// run maintenance in a background task, controlled with
+    /// `Db::maintenance`. the task stops once every handle to the database
+    /// is dropped. read-only handles don't run maintenance.
+    pub fn maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.options.maintenance = Some(maintenance);
+        self
+    }
+
+    /// how values are serialized, CBOR by default.
+    /// a database must always be opened with the same codec.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.options.codec = codec;
+        self
+    }
+}
+
+impl Builder<Append> {
+    /// make `delete` append a tombstone instead of removing the key and its
+    /// values, like `Db::delete_keeping_history`.
+    /// deleted keys read as missing, while `read_as_of` and `read_range`
+    /// still see their earlier values.
+    pub fn tombstone_deletes(mut self) -> Self {
+        self.options.tombstones = true;
+        self
+    }
+
+    /// how long `collect_garbage` keeps a key whose latest entry is a
+    /// tombstone before removing it, 0 by default.
+    /// until then the key's earlier versions are kept too, whatever the
+    /// `Retention`, so `read_as_of` and `read_range` still see them.
+    pub fn tombstone_retention(mut self, retention: std::time::Duration) -> Self {
+        self.options.tombstone_retention = retention;
+        self
+    }
+}
+
+/// a `Builder` for a read-only handle, from `Builder::read_only`
+pub struct ReadOnlyBuilder<T> {
+    builder: Builder<T>,
+}
+
+impl<T> ReadOnlyBuilder<T> {
+    pub async fn finish(self) -> Result<ReadOnlyDb<T>, Error>
+    where
+        T: Storage,
+    {
+        let db = self.builder.finish().await?;
+        Ok(ReadOnlyDb::new(db))
+    }
+}
+
+/// how many rows the `stream_*` functions read at a time
+const STREAM_PAGE_SIZE: u32 = 1000;
+
+fn bound_as_ref(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    bound.as_ref().map(|k| &k[..])
+}
+
+/// format a time the way sqlite stores `inserted_at`
+fn sqlite_timestamp(t: DateTime<Utc>) -> String {
+    t.format("%Y-%m-%d %H:%M:%S%.3f").to_string()
 }
 
 #[derive(Default)]
 pub struct Options {
     in_memory: bool,
     db_path: Option<PathBuf>,
+    read_only: bool,
+    codec: Codec,
+    change_log: bool,
+    min_connections: Option<u32>,
+    max_connections: Option<u32>,
+    busy_timeout: Option<std::time::Duration>,
+    journal_mode: Option<JournalMode>,
+    synchronous: Option<Synchronous>,
+    page_cache_size: Option<i64>,
+    mmap_size: Option<u64>,
+    pragmas: Vec<(String, String)>,
+    tombstones: bool,
+    tombstone_retention: std::time::Duration,
+    maintenance: Option<Maintenance>,
+}
+
+/// sqlite's `journal_mode`, how it makes transactions atomic
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+/// sqlite's `synchronous` setting, how often it waits for writes
+/// to reach the disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
 }
 
 // #[cfg(test)]
@@ -0,0 +1,251 @@
+use crate::begin_immediate::SqliteConnectionExt;
+use crate::storage::Storage;
+use crate::{Error, GarbageCollected, Retention};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// settings for a background task that keeps a database tidy,
+/// started by `Builder::maintenance`.
+/// each run collects garbage (for `Append`) and purges expired keys in every
+/// bucket, then runs `incremental_vacuum` and `wal_checkpoint(TRUNCATE)`
+/// if their thresholds are reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Maintenance {
+    interval: Duration,
+    retention: Option<Retention>,
+    purge_expired: bool,
+    vacuum_threshold: Option<u32>,
+    checkpoint_threshold: Option<u32>,
+}
+
+impl Maintenance {
+    /// run every `interval`, collecting garbage with `Retention::latest`,
+    /// purging expired keys, vacuuming once 1000 pages are free and
+    /// checkpointing once the WAL has 1000 frames
+    pub fn every(interval: Duration) -> Self {
+        Self {
+            interval,
+            retention: Some(Retention::latest()),
+            purge_expired: true,
+            vacuum_threshold: Some(1000),
+            checkpoint_threshold: Some(1000),
+        }
+    }
+
+    /// collect garbage with `retention`, or not at all if `None`.
+    /// only `Append` databases have garbage.
+    pub fn collect_garbage(mut self, retention: Option<Retention>) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// whether to purge expired keys
+    pub fn purge_expired(mut self, purge_expired: bool) -> Self {
+        self.purge_expired = purge_expired;
+        self
+    }
+
+    /// run `incremental_vacuum` once the database has this many free pages,
+    /// or never if `None`.
+    /// this only frees pages in databases created with
+    /// `Builder::pragma("auto_vacuum", "incremental")`.
+    pub fn vacuum_threshold(mut self, pages: Option<u32>) -> Self {
+        self.vacuum_threshold = pages;
+        self
+    }
+
+    /// run `wal_checkpoint(TRUNCATE)` once the WAL has this many frames,
+    /// or never if `None`
+    pub fn checkpoint_threshold(mut self, frames: Option<u32>) -> Self {
+        self.checkpoint_threshold = frames;
+        self
+    }
+}
+
+/// what a maintenance run did
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    /// the garbage collected across every bucket
+    pub garbage: GarbageCollected,
+    /// the number of expired keys purged across every bucket
+    pub expired: u64,
+    /// whether `incremental_vacuum` ran
+    pub vacuumed: bool,
+    /// whether `wal_checkpoint(TRUNCATE)` ran
+    pub checkpointed: bool,
+}
+
+/// a handle to a database's maintenance task, from `Db::maintenance`.
+/// it doesn't keep the task running: the task stops once every `Db`
+/// handle to the database is dropped.
+#[derive(Clone, Debug)]
+pub struct MaintenanceHandle {
+    commands: mpsc::WeakUnboundedSender<Command>,
+}
+
+impl MaintenanceHandle {
+    pub(crate) fn new(commands: &mpsc::UnboundedSender<Command>) -> Self {
+        Self {
+            commands: commands.downgrade(),
+        }
+    }
+
+    /// stop running on the interval until `resume` is called.
+    /// a run in progress finishes.
+    pub fn pause(&self) -> Result<(), Error> {
+        self.send(Command::Pause)
+    }
+
+    /// start running on the interval again
+    pub fn resume(&self) -> Result<(), Error> {
+        self.send(Command::Resume)
+    }
+
+    /// run now, even if paused, and wait for the run to finish
+    pub async fn trigger(&self) -> Result<MaintenanceReport, Error> {
+        let (done, report) = oneshot::channel();
+
+        self.send(Command::Run(done))?;
+
+        report.await.map_err(|_| Error::MaintenanceStopped)?
+    }
+
+    fn send(&self, command: Command) -> Result<(), Error> {
+        self.commands
+            .upgrade()
+            .ok_or(Error::MaintenanceStopped)?
+            .send(command)
+            .map_err(|_| Error::MaintenanceStopped)
+    }
+}
+
+/// a running maintenance task and the settings it was started with
+#[derive(Clone, Debug)]
+pub(crate) struct Task {
+    settings: Maintenance,
+    /// keeps the task running
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Task {
+    pub(crate) fn start<T>(storage: &T, settings: Maintenance) -> Self
+    where
+        T: Storage,
+    {
+        Self {
+            settings,
+            commands: storage.start_maintenance(settings),
+        }
+    }
+
+    pub(crate) fn settings(&self) -> Maintenance {
+        self.settings
+    }
+
+    pub(crate) fn handle(&self) -> MaintenanceHandle {
+        MaintenanceHandle::new(&self.commands)
+    }
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Pause,
+    Resume,
+    Run(oneshot::Sender<Result<MaintenanceReport, Error>>),
+}
+
+/// run maintenance every `maintenance.interval` until every sender of
+/// `commands` is dropped
+pub(crate) async fn run<T>(
+    storage: T,
+    maintenance: Maintenance,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) where
+    T: Storage,
+{
+    let mut interval =
+        tokio::time::interval_at(Instant::now() + maintenance.interval, maintenance.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut paused = false;
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(Command::Pause) => paused = true,
+                Some(Command::Resume) => paused = false,
+                Some(Command::Run(done)) => {
+                    let _ = done.send(run_once(&storage, &maintenance).await);
+                }
+                None => return,
+            },
+            _ = interval.tick(), if !paused => {
+                // a failed run is tried again on the next tick
+                let _ = run_once(&storage, &maintenance).await;
+            }
+        }
+    }
+}
+
+async fn run_once<T>(storage: &T, maintenance: &Maintenance) -> Result<MaintenanceReport, Error>
+where
+    T: Storage,
+{
+    let mut report = MaintenanceReport::default();
+
+    let buckets = {
+        let mut conn = storage.pool().acquire().await?;
+        storage.buckets(&mut conn).await?
+    };
+
+    for bucket in buckets {
+        let storage = storage.with_bucket(&bucket);
+
+        if let Some(retention) = maintenance.retention {
+            let garbage = storage.collect_garbage(retention).await?;
+            report.garbage.versions += garbage.versions;
+            report.garbage.keys += garbage.keys;
+            report.garbage.bytes += garbage.bytes;
+        }
+
+        if maintenance.purge_expired {
+            let mut conn = storage.pool().acquire().await?;
+            let mut tx = conn.begin_immediate().await?;
+            report.expired += storage.purge_expired(&mut tx).await?;
+            tx.commit().await?;
+        }
+    }
+
+    let mut conn = storage.pool().acquire().await?;
+
+    if let Some(threshold) = maintenance.vacuum_threshold {
+        let (free_pages,): (i64,) = sqlx::query_as("pragma freelist_count")
+            .fetch_one(&mut *conn)
+            .await?;
+
+        if free_pages >= i64::from(threshold) {
+            sqlx::query("pragma incremental_vacuum")
+                .execute(&mut *conn)
+                .await?;
+            report.vacuumed = true;
+        }
+    }
+
+    if let Some(threshold) = maintenance.checkpoint_threshold {
+        // a passive checkpoint doesn't wait for anything,
+        // and reports the WAL's size in frames, or -1 if it isn't in WAL mode
+        let (_, frames, _): (i64, i64, i64) = sqlx::query_as("pragma wal_checkpoint(PASSIVE)")
+            .fetch_one(&mut *conn)
+            .await?;
+
+        if frames >= i64::from(threshold) {
+            sqlx::query("pragma wal_checkpoint(TRUNCATE)")
+                .execute(&mut *conn)
+                .await?;
+            report.checkpointed = true;
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,149 @@
+use crate::storage::append::Append;
+use crate::storage::Storage;
+use crate::{AsOf, Change, Db, Error, Order, Revision, Version};
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use serde::de::DeserializeOwned;
+use std::ops::RangeBounds;
+
+/// a handle to a database opened with `Builder::read_only`.
+/// it only has the methods of `Db` that read, so it can't change the database.
+#[derive(Clone, Debug)]
+pub struct ReadOnlyDb<T>
+where
+    T: Storage,
+{
+    pub(crate) db: Db<T>,
+}
+
+impl<T> ReadOnlyDb<T>
+where
+    T: Storage,
+{
+    pub(crate) fn new(db: Db<T>) -> Self {
+        Self { db }
+    }
+
+    /// read a value
+    pub async fn read<K, V>(&self, key: &K) -> Result<Option<V>, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+    {
+        self.db.read(key).await
+    }
+
+    /// read many values from a single snapshot of the database,
+    /// like `Db::read_many`
+    pub async fn read_many<K, V, I>(&self, keys: I) -> Result<Vec<Option<V>>, Error>
+    where
+        K: AsRef<[u8]>,
+        V: DeserializeOwned,
+        I: IntoIterator<Item = K>,
+    {
+        self.db.read_many(keys).await
+    }
+
+    /// read a value and its current revision
+    pub async fn read_with_revision<K, V>(&self, key: &K) -> Result<Option<(V, Revision)>, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+    {
+        self.db.read_with_revision(key).await
+    }
+
+    /// read the key/values with keys in the given range, in key order
+    pub async fn range<K, V, R>(&self, range: R) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        K: AsRef<[u8]>,
+        V: DeserializeOwned,
+        R: RangeBounds<K>,
+    {
+        self.db.range(range).await
+    }
+
+    /// read the key/values with keys starting with `prefix`, in key order
+    pub async fn prefix<P, V>(&self, prefix: &P) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        P: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+    {
+        self.db.prefix(prefix).await
+    }
+
+    /// stream the current keys in key order, like `Db::stream_keys`
+    pub fn stream_keys(&self) -> impl Stream<Item = Result<Vec<u8>, Error>> + '_ {
+        self.db.stream_keys()
+    }
+
+    /// stream the current key/values in key order, like `Db::stream_entries`
+    pub fn stream_entries<V>(&self) -> impl Stream<Item = Result<(Vec<u8>, V), Error>> + '_
+    where
+        V: DeserializeOwned,
+    {
+        self.db.stream_entries()
+    }
+
+    /// a read-only handle to the bucket named `name`, like `Db::bucket`
+    pub fn bucket(&self, name: &str) -> ReadOnlyDb<T> {
+        ReadOnlyDb::new(self.db.bucket(name))
+    }
+
+    /// read up to `limit` changes from the change log, like `Db::changes`
+    pub async fn changes(&self, after: u64, limit: u32) -> Result<Vec<Change>, Error> {
+        self.db.changes(after, limit).await
+    }
+
+    /// stream the changes with sequence numbers greater than `after`,
+    /// like `Db::tail_changes`
+    pub fn tail_changes(
+        &self,
+        after: u64,
+        poll_interval: std::time::Duration,
+    ) -> impl Stream<Item = Result<Change, Error>> + '_ {
+        self.db.tail_changes(after, poll_interval)
+    }
+
+    /// get the current keys
+    pub async fn keys(&self) -> Result<Vec<Vec<u8>>, Error> {
+        self.db.keys().await
+    }
+
+    /// get the current number of keys
+    pub async fn keys_count(&self) -> Result<u64, Error> {
+        self.db.keys_count().await
+    }
+}
+
+impl ReadOnlyDb<Append> {
+    /// the total number of entries, including duplicates and deletes
+    pub async fn entries_count(&self) -> Result<u64, Error> {
+        self.db.entries_count().await
+    }
+
+    /// read the latest value of a key at or before the given time
+    pub async fn read_as_of<K, V>(&self, key: &K, as_of: DateTime<Utc>) -> Result<AsOf<V>, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+    {
+        self.db.read_as_of(key, as_of).await
+    }
+
+    /// stream every version of a key inserted within the given time range,
+    /// including tombstones
+    pub fn read_range<K, V, R>(
+        &self,
+        key: &K,
+        range: R,
+        order: Order,
+    ) -> impl Stream<Item = Result<Version<V>, Error>> + '_
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+        R: RangeBounds<DateTime<Utc>>,
+    {
+        self.db.read_range(key, range, order)
+    }
+}
@@ -0,0 +1,264 @@
+use crate::begin_immediate::SqliteConnectionExt;
+use crate::{sqlite_timestamp, Error};
+use chrono::{DateTime, Utc};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::time::Duration;
+
+/// how many keys are collected in each transaction
+const BATCH_SIZE: u32 = 1000;
+
+/// which versions of each key `Db::collect_garbage_with` keeps.
+/// a key's latest version is always kept, and an older version is kept
+/// if any of the rules keeps it.
+/// the default keeps only the latest version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    last: Option<u32>,
+    newer_than: Option<Duration>,
+    daily_for: Option<Duration>,
+}
+
+impl Retention {
+    /// keep only each key's latest version
+    pub fn latest() -> Self {
+        Self::default()
+    }
+
+    /// keep each key's `n` latest versions
+    pub fn keep_last(mut self, n: u32) -> Self {
+        self.last = Some(n);
+        self
+    }
+
+    /// keep versions written less than `age` ago
+    pub fn keep_newer_than(mut self, age: Duration) -> Self {
+        self.newer_than = Some(age);
+        self
+    }
+
+    /// keep the last version written on each day (in UTC),
+    /// for days less than `period` ago
+    pub fn keep_daily_for(mut self, period: Duration) -> Self {
+        self.daily_for = Some(period);
+        self
+    }
+}
+
+/// what a garbage collection removed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GarbageCollected {
+    /// the number of versions removed, including tombstones
+    pub versions: u64,
+    /// the number of keys removed because they were deleted
+    pub keys: u64,
+    /// the size of the values removed, in bytes
+    pub bytes: u64,
+}
+
+/// delete the versions in `bucket` that `retention` doesn't keep,
+/// a batch of keys at a time, each in its own transaction
+/// so writers only wait for one batch
+pub(crate) async fn collect(
+    pool: &SqlitePool,
+    bucket: &str,
+    retention: Retention,
+    tombstone_retention: Duration,
+) -> Result<GarbageCollected, Error> {
+    let cutoffs = Cutoffs::new(retention, tombstone_retention);
+
+    let mut collected = GarbageCollected::default();
+
+    let mut after = 0;
+
+    loop {
+        let mut conn = pool.acquire().await?;
+
+        let mut tx = conn.begin_immediate().await?;
+
+        let last = collect_batch(&mut tx, bucket, &cutoffs, after, &mut collected).await?;
+
+        tx.commit().await?;
+
+        match last {
+            Some(last) => after = last,
+            None => return Ok(collected),
+        }
+    }
+}
+
+/// the times before which `Retention`'s rules and the tombstone retention
+/// stop keeping versions, worked out once for a whole collection
+struct Cutoffs {
+    last: Option<u32>,
+    newer_than: Option<String>,
+    daily_for: Option<String>,
+    tombstones: String,
+}
+
+impl Cutoffs {
+    fn new(retention: Retention, tombstone_retention: Duration) -> Self {
+        let now = Utc::now();
+
+        Self {
+            last: retention.last,
+            newer_than: retention.newer_than.map(|age| before(now, age)),
+            daily_for: retention.daily_for.map(|period| before(now, period)),
+            tombstones: before(now, tombstone_retention),
+        }
+    }
+}
+
+fn before(now: DateTime<Utc>, age: Duration) -> String {
+    let t = chrono::Duration::from_std(age)
+        .ok()
+        .and_then(|age| now.checked_sub_signed(age))
+        .unwrap_or(DateTime::<Utc>::MIN_UTC);
+
+    sqlite_timestamp(t)
+}
+
+/// the keys in a batch whose latest version is a tombstone
+/// older than the tombstone retention
+const DELETED_KEYS: &str = "
+    select
+        keys.id
+    from keys
+    inner join vvalues
+        on vvalues.id = (
+            select
+                latest.id
+            from vvalues latest
+            where latest.key_id = keys.id
+            order by latest.id desc
+            limit 1
+        )
+    where keys.bucket = ?1
+    and keys.id > ?2
+    and keys.id <= ?3
+    and vvalues.value is null
+    and vvalues.inserted_at <= ?4
+";
+
+/// collect the garbage of a batch of keys in `bucket` with ids greater
+/// than `after`, returning the id of the last key in the batch,
+/// or `None` if there were no keys left
+async fn collect_batch(
+    conn: &mut SqliteConnection,
+    bucket: &str,
+    cutoffs: &Cutoffs,
+    after: i64,
+    collected: &mut GarbageCollected,
+) -> Result<Option<i64>, Error> {
+    let (last,): (Option<i64>,) = sqlx::query_as(
+        "
+        select
+            max(id)
+        from (
+            select
+                id
+            from keys
+            where bucket = ?
+            and id > ?
+            order by id
+            limit ?
+        )
+        ",
+    )
+    .bind(bucket)
+    .bind(after)
+    .bind(BATCH_SIZE)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let Some(last) = last else {
+        return Ok(None);
+    };
+
+    let removed: Vec<(i64,)> = sqlx::query_as(
+        "
+        with ranked as (
+            select
+                vvalues.id,
+                vvalues.inserted_at,
+                row_number() over (
+                    partition by vvalues.key_id
+                    order by vvalues.id desc
+                ) as recency,
+                row_number() over (
+                    partition by vvalues.key_id, date(vvalues.inserted_at)
+                    order by vvalues.id desc
+                ) as daily_recency,
+                first_value(vvalues.value is null and vvalues.inserted_at > ?7) over (
+                    partition by vvalues.key_id
+                    order by vvalues.id desc
+                ) as retained_tombstone
+            from keys
+            inner join vvalues
+                on vvalues.key_id = keys.id
+            where keys.bucket = ?1
+            and keys.id > ?2
+            and keys.id <= ?3
+        )
+        delete from vvalues
+        where id in (
+            select
+                id
+            from ranked
+            where recency > 1
+            -- a deleted key keeps its history until its tombstone expires
+            and not retained_tombstone
+            and (?4 is null or recency > ?4)
+            and (?5 is null or inserted_at <= ?5)
+            and (?6 is null or daily_recency > 1 or inserted_at <= ?6)
+        )
+        returning coalesce(length(cast(value as blob)), 0)
+        ",
+    )
+    .bind(bucket)
+    .bind(after)
+    .bind(last)
+    .bind(cutoffs.last)
+    .bind(&cutoffs.newer_than)
+    .bind(&cutoffs.daily_for)
+    .bind(&cutoffs.tombstones)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    collected.versions += removed.len() as u64;
+    collected.bytes += removed.iter().map(|(bytes,)| *bytes as u64).sum::<u64>();
+
+    let (versions, bytes): (i64, i64) = sqlx::query_as(&format!(
+        "
+        select
+            count(*),
+            coalesce(sum(length(cast(value as blob))), 0)
+        from vvalues
+        where key_id in ({DELETED_KEYS})
+        "
+    ))
+    .bind(bucket)
+    .bind(after)
+    .bind(last)
+    .bind(&cutoffs.tombstones)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let deleted = sqlx::query(&format!(
+        "
+        delete from keys
+        where id in ({DELETED_KEYS})
+        "
+    ))
+    .bind(bucket)
+    .bind(after)
+    .bind(last)
+    .bind(&cutoffs.tombstones)
+    .execute(&mut *conn)
+    .await?;
+
+    collected.versions += versions as u64;
+    collected.bytes += bytes as u64;
+    collected.keys += deleted.rows_affected();
+
+    Ok(Some(last))
+}
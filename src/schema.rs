@@ -0,0 +1,622 @@
+use crate::storage::{append, update_in_place};
+use crate::{Codec, Error};
+use sqlx::SqliteConnection;
+
+/// the version of the on-disk format this version of kvqlite reads and writes.
+/// when the layout of the tables changes, bump it and add a migration
+/// from the previous version to `migrate`.
+pub(crate) const FORMAT_VERSION: u32 = 2;
+
+/// how a database stores its key/values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    UpdateInPlace,
+    Append,
+}
+
+impl Strategy {
+    /// the name recorded in the database's metadata
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Strategy::UpdateInPlace => "update_in_place",
+            Strategy::Append => "append",
+        }
+    }
+
+    /// the strategy recorded in the database's metadata as `name`
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "update_in_place" => Some(Strategy::UpdateInPlace),
+            "append" => Some(Strategy::Append),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// create the metadata table if the database doesn't have one and migrate
+/// the database to `FORMAT_VERSION`, recording the strategy and codec
+/// of a new database
+pub(crate) async fn open(
+    conn: &mut SqliteConnection,
+    strategy: Strategy,
+    codec: Codec,
+) -> Result<(), Error> {
+    sqlx::query(
+        "
+        create table if not exists meta (
+            name text not null primary key,
+            value text not null
+        )
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let version = format_version(conn).await?;
+
+    if version > FORMAT_VERSION {
+        return Err(Error::UnsupportedFormatVersion(version));
+    }
+
+    for from in version..FORMAT_VERSION {
+        migrate(conn, from).await?;
+    }
+
+    set(conn, "format_version", &FORMAT_VERSION.to_string()).await?;
+
+    let found = match recorded_strategy(conn).await? {
+        Some(found) => Some(found),
+        None => unrecorded_strategy(conn).await?,
+    };
+
+    if let Some(found) = found {
+        if found != strategy {
+            return Err(Error::StrategyMismatch(found));
+        }
+    }
+
+    set(conn, "strategy", strategy.name()).await?;
+
+    sqlx::query(
+        "
+        insert or ignore into meta (name, value)
+        values('codec', ?)
+        ",
+    )
+    .bind(codec.name())
+    .execute(&mut *conn)
+    .await?;
+
+    check_codec(conn, codec).await
+}
+
+/// check that a database opened read-only doesn't need migrating
+/// and uses `strategy` and `codec`
+pub(crate) async fn check(
+    conn: &mut SqliteConnection,
+    strategy: Strategy,
+    codec: Codec,
+) -> Result<(), Error> {
+    let version = if table_exists(conn, "meta").await? {
+        format_version(conn).await?
+    } else {
+        0
+    };
+
+    if version > FORMAT_VERSION {
+        return Err(Error::UnsupportedFormatVersion(version));
+    } else if version < FORMAT_VERSION {
+        return Err(Error::MigrationNeeded(version));
+    }
+
+    if let Some(found) = recorded_strategy(conn).await? {
+        if found != strategy {
+            return Err(Error::StrategyMismatch(found));
+        }
+    }
+
+    check_codec(conn, codec).await
+}
+
+/// check that the database's values are serialized with `codec`,
+/// if it has recorded a codec
+async fn check_codec(conn: &mut SqliteConnection, codec: Codec) -> Result<(), Error> {
+    let Some(found) = get(conn, "codec").await? else {
+        return Ok(());
+    };
+
+    let found = Codec::from_name(&found).ok_or_else(|| {
+        Error::Deserialization(format!("the database's codec {found} is unknown").into())
+    })?;
+
+    if found != codec {
+        return Err(Error::CodecMismatch(found));
+    }
+
+    Ok(())
+}
+
+/// check that a backup about to be restored is intact, isn't from a newer
+/// version of kvqlite, and has the tables of `strategy`.
+/// backups from older format versions are migrated when they are opened.
+pub(crate) async fn check_backup(
+    conn: &mut SqliteConnection,
+    strategy: Strategy,
+) -> Result<(), Error> {
+    let (integrity,): (String,) = sqlx::query_as("pragma quick_check(1)")
+        .fetch_one(&mut *conn)
+        .await?;
+
+    if integrity != "ok" {
+        return Err(Error::InvalidBackup(integrity));
+    }
+
+    // backups from before the metadata table are version 0
+    let has_meta = table_exists(conn, "meta").await?;
+
+    let version = if has_meta {
+        format_version(conn).await?
+    } else {
+        0
+    };
+
+    if version > FORMAT_VERSION {
+        return Err(Error::UnsupportedFormatVersion(version));
+    }
+
+    let recorded = if has_meta {
+        recorded_strategy(conn).await?
+    } else {
+        None
+    };
+
+    let found = match recorded {
+        Some(found) => Some(found),
+        None => unrecorded_strategy(conn).await?,
+    };
+
+    if let Some(found) = found {
+        if found != strategy {
+            return Err(Error::StrategyMismatch(found));
+        }
+    }
+
+    let tables: &[&str] = match strategy {
+        Strategy::UpdateInPlace => &["kvs"],
+        Strategy::Append => &["keys", "vvalues"],
+    };
+
+    for table in tables {
+        if !table_exists(conn, table).await? {
+            return Err(Error::InvalidBackup(format!("it has no {table} table")));
+        }
+    }
+
+    Ok(())
+}
+
+/// the database's format version.
+/// databases from before the metadata table are version 0,
+/// and a database without any tables yet is new.
+async fn format_version(conn: &mut SqliteConnection) -> Result<u32, Error> {
+    if let Some(version) = get(conn, "format_version").await? {
+        version
+            .parse()
+            .map_err(|e: std::num::ParseIntError| Error::Deserialization(e.into()))
+    } else if table_exists(conn, "kvs").await? || table_exists(conn, "keys").await? {
+        Ok(0)
+    } else {
+        Ok(FORMAT_VERSION)
+    }
+}
+
+/// the strategy recorded in the metadata table, if there is one
+async fn recorded_strategy(conn: &mut SqliteConnection) -> Result<Option<Strategy>, Error> {
+    let Some(found) = get(conn, "strategy").await? else {
+        return Ok(None);
+    };
+
+    let found = Strategy::from_name(&found).ok_or_else(|| {
+        Error::Deserialization(format!("the database's strategy {found} is unknown").into())
+    })?;
+
+    Ok(Some(found))
+}
+
+/// the strategy of a database from before the metadata table,
+/// going by which strategy's tables have key/values in them.
+/// a database with key/values in both can't be opened with either.
+async fn unrecorded_strategy(conn: &mut SqliteConnection) -> Result<Option<Strategy>, Error> {
+    let update_in_place = has_rows(conn, "kvs").await?;
+    let append = has_rows(conn, "keys").await?;
+
+    match (update_in_place, append) {
+        (true, true) => Err(Error::AmbiguousStrategy),
+        (true, false) => Ok(Some(Strategy::UpdateInPlace)),
+        (false, true) => Ok(Some(Strategy::Append)),
+        (false, false) => Ok(None),
+    }
+}
+
+async fn get(conn: &mut SqliteConnection, name: &str) -> Result<Option<String>, Error> {
+    let value: Option<(String,)> = sqlx::query_as(
+        "
+        select
+            value
+        from meta
+        where name = ?
+        ",
+    )
+    .bind(name)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(value.map(|(value,)| value))
+}
+
+async fn set(conn: &mut SqliteConnection, name: &str, value: &str) -> Result<(), Error> {
+    sqlx::query(
+        "
+        insert into meta (name, value)
+        values(?, ?)
+        on conflict(name) do update set value = excluded.value
+        ",
+    )
+    .bind(name)
+    .bind(value)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// migrate a database from format version `from` to `from + 1`
+async fn migrate(conn: &mut SqliteConnection, from: u32) -> Result<(), Error> {
+    match from {
+        0 => migrate_v0(conn).await,
+        1 => migrate_v1(conn).await,
+        _ => unreachable!("no migration from format version {from}"),
+    }
+}
+
+/// bring a database from before the metadata table up to version 1.
+/// these databases have the tables of any earlier layout, so each table
+/// is checked for the columns it is missing.
+async fn migrate_v0(conn: &mut SqliteConnection) -> Result<(), Error> {
+    // buckets changed the primary key of kvs, which means rebuilding it
+    if table_exists(conn, "kvs").await? && !has_column(conn, "kvs", "bucket").await? {
+        sqlx::query("alter table kvs rename to kvs_v0")
+            .execute(&mut *conn)
+            .await?;
+
+        update_in_place::create_tables(conn).await?;
+
+        let columns = shared_columns(
+            conn,
+            "kvs_v0",
+            &[
+                "key",
+                "value",
+                "version",
+                "expires_at",
+                "inserted_at",
+                "updated_at",
+            ],
+        )
+        .await?;
+
+        sqlx::query(&format!(
+            "
+            insert into kvs ({columns})
+            select {columns} from kvs_v0
+            "
+        ))
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query("drop table kvs_v0").execute(&mut *conn).await?;
+    }
+
+    if table_exists(conn, "keys").await? {
+        if !has_column(conn, "keys", "bucket").await? {
+            // recreated on (bucket, key) by `append::create_tables`
+            sqlx::query("drop index if exists keys_key")
+                .execute(&mut *conn)
+                .await?;
+
+            sqlx::query("alter table keys add column bucket text not null default ''")
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        if !has_column(conn, "keys", "expires_at").await? {
+            sqlx::query("alter table keys add column expires_at datetime")
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+
+    // tombstones made vvalues.value nullable, which means rebuilding it.
+    // it is the child of keys, so rebuilding it doesn't cascade.
+    let (value_not_null,): (bool,) = sqlx::query_as(
+        "
+        select
+            count(*) > 0
+        from pragma_table_info('vvalues')
+        where name = 'value'
+        and \"notnull\"
+        ",
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    if value_not_null {
+        rebuild_vvalues(conn).await?;
+    }
+
+    if table_exists(conn, "changes").await? && !has_column(conn, "changes", "bucket").await? {
+        sqlx::query("alter table changes add column bucket text not null default ''")
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// bring a version 1 database up to version 2, where revisions are never
+/// handed out twice: `UpdateInPlace` draws them from a counter in `meta`,
+/// and `Append` draws them from an autoincrementing `vvalues.id`
+async fn migrate_v1(conn: &mut SqliteConnection) -> Result<(), Error> {
+    if table_exists(conn, "kvs").await? {
+        sqlx::query(
+            "
+            insert or ignore into meta (name, value)
+            select 'revision', coalesce(max(version), 0) from kvs
+            ",
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    let (autoincrement,): (bool,) = sqlx::query_as(
+        "
+        select
+            count(*) > 0
+        from sqlite_master
+        where name = 'vvalues'
+        and sql like '%autoincrement%'
+        ",
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    // databases migrated from version 0 already have the new vvalues
+    if table_exists(conn, "vvalues").await? && !autoincrement {
+        rebuild_vvalues(conn).await?;
+    }
+
+    Ok(())
+}
+
+/// recreate vvalues with the layout `append::create_tables` gives it,
+/// keeping its rows and their ids
+async fn rebuild_vvalues(conn: &mut SqliteConnection) -> Result<(), Error> {
+    for index in ["vvalues_inserted_at", "vvalues_key_id"] {
+        sqlx::query(&format!("drop index if exists {index}"))
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    sqlx::query("alter table vvalues rename to vvalues_old")
+        .execute(&mut *conn)
+        .await?;
+
+    append::create_tables(conn).await?;
+
+    sqlx::query(
+        "
+        insert into vvalues (id, key_id, value, inserted_at)
+        select id, key_id, value, inserted_at from vvalues_old
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query("drop table vvalues_old")
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// the next revision of a key written to an `UpdateInPlace` database.
+/// the counter only goes up, so a key that is deleted and written again
+/// never gets back a revision it had before.
+pub(crate) async fn next_revision(conn: &mut SqliteConnection) -> Result<i64, Error> {
+    let (revision,): (i64,) = sqlx::query_as(
+        "
+        insert into meta (name, value)
+        values('revision', 1)
+        on conflict(name) do update set value = value + 1
+        returning cast(value as integer)
+        ",
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(revision)
+}
+
+/// convert an `Append` database to `UpdateInPlace`,
+/// keeping only the latest value of each key
+pub(crate) async fn append_to_update_in_place(conn: &mut SqliteConnection) -> Result<(), Error> {
+    update_in_place::create_tables(conn).await?;
+
+    sqlx::query(
+        "
+        insert into kvs (bucket, key, value, version, expires_at, inserted_at, updated_at)
+        select
+            keys.bucket,
+            keys.key,
+            vvalues.value,
+            vvalues.id,
+            keys.expires_at,
+            keys.inserted_at,
+            vvalues.inserted_at
+        from keys
+        inner join vvalues
+            on vvalues.id = (
+                select
+                    latest.id
+                from vvalues latest
+                where latest.key_id = keys.id
+                order by latest.id desc
+                limit 1
+            )
+        -- keys whose latest value is a tombstone are deleted
+        where vvalues.value is not null
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    // carry on from the revisions the keys had,
+    // including those of keys that have been deleted
+    sqlx::query(
+        "
+        insert into meta (name, value)
+        select 'revision', coalesce(max(seq), 0) from sqlite_sequence where name = 'vvalues'
+        on conflict(name) do update set value = max(cast(value as integer), excluded.value)
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    // vvalues first, so dropping keys has nothing to cascade to
+    sqlx::query("drop table vvalues")
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query("drop table keys").execute(&mut *conn).await?;
+
+    set(conn, "strategy", Strategy::UpdateInPlace.name()).await
+}
+
+/// convert an `UpdateInPlace` database to `Append`,
+/// with each key's current value as its only version
+pub(crate) async fn update_in_place_to_append(conn: &mut SqliteConnection) -> Result<(), Error> {
+    append::create_tables(conn).await?;
+
+    // new ids carry on from the revisions the keys had
+    sqlx::query(
+        "
+        insert into sqlite_sequence (name, seq)
+        select 'vvalues', cast(value as integer) from meta where name = 'revision'
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "
+        insert into keys (bucket, key, expires_at, inserted_at)
+        select
+            bucket,
+            key,
+            expires_at,
+            inserted_at
+        from kvs
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "
+        insert into vvalues (key_id, value, inserted_at)
+        select
+            keys.id,
+            kvs.value,
+            kvs.updated_at
+        from kvs
+        inner join keys
+            on keys.bucket = kvs.bucket
+            and keys.key = kvs.key
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query("drop table kvs").execute(&mut *conn).await?;
+
+    set(conn, "strategy", Strategy::Append.name()).await
+}
+
+async fn table_exists(conn: &mut SqliteConnection, name: &str) -> Result<bool, Error> {
+    let (exists,): (bool,) = sqlx::query_as(
+        "
+        select
+            count(*) > 0
+        from sqlite_master
+        where type = 'table'
+        and name = ?
+        ",
+    )
+    .bind(name)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(exists)
+}
+
+async fn has_rows(conn: &mut SqliteConnection, table: &str) -> Result<bool, Error> {
+    if !table_exists(conn, table).await? {
+        return Ok(false);
+    }
+
+    let (has_rows,): (bool,) = sqlx::query_as(&format!("select exists(select 1 from {table})"))
+        .fetch_one(&mut *conn)
+        .await?;
+
+    Ok(has_rows)
+}
+
+async fn has_column(conn: &mut SqliteConnection, table: &str, column: &str) -> Result<bool, Error> {
+    let (exists,): (bool,) = sqlx::query_as(
+        "
+        select
+            count(*) > 0
+        from pragma_table_info(?)
+        where name = ?
+        ",
+    )
+    .bind(table)
+    .bind(column)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(exists)
+}
+
+/// the comma separated `columns` that `table` has
+async fn shared_columns(
+    conn: &mut SqliteConnection,
+    table: &str,
+    columns: &[&str],
+) -> Result<String, Error> {
+    let mut shared = vec![];
+
+    for column in columns {
+        if has_column(conn, table, column).await? {
+            shared.push(*column);
+        }
+    }
+
+    Ok(shared.join(", "))
+}
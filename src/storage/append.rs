@@ -1,173 +1,221 @@
-use super::{private, Storage};
-use crate::{begin_immediate::SqliteConnectionExt, Error, Options};
-use serde::{de::DeserializeOwned, Serialize};
-use std::str::FromStr;
-
-#[derive(Debug)]
+use super::{connect, private, range_condition, sql_limit, Storage, EVENTS_CAPACITY};
+use crate::{
+    begin_immediate::SqliteConnectionExt,
+    changes,
+    codec::EncodedValue,
+    index,
+    index::Indexes,
+    maintenance::{self, Command, Maintenance},
+    retention, schema,
+    schema::Strategy,
+    sqlite_timestamp, Codec, Error, Event, EventKind, GarbageCollected, Options, Retention,
+    Revision,
+};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::ops::Bound;
+use tokio::sync::{broadcast, mpsc};
+
+#[derive(Clone, Debug)]
 pub struct Append {
     pub(crate) pool: sqlx::sqlite::SqlitePool,
+    pub(crate) codec: Codec,
+    pub(crate) events: broadcast::Sender<Event>,
+    pub(crate) bucket: String,
+    pub(crate) indexes: Indexes,
+    pub(crate) tombstones: bool,
+    pub(crate) tombstone_retention: std::time::Duration,
 }
 
 impl private::Sealed for Append {}
 
 impl Storage for Append {
+    const STRATEGY: Strategy = Strategy::Append;
+
     async fn open(options: Options) -> Result<Self, Error>
     where
         Self: Sized,
     {
-        let db_path = if options.in_memory {
-            "sqlite::memory:".to_string()
-        } else if let Some(p) = options.db_path {
-            p.to_str().unwrap().to_string()
+        let pool = connect(&options).await?;
+
+        let mut conn = pool.acquire().await?;
+
+        // a read-only handle can't create or migrate tables,
+        // so they must already exist
+        if options.read_only {
+            schema::check(&mut conn, Self::STRATEGY, options.codec).await?;
         } else {
-            "sqlite://kvqlite.db".to_string()
-        };
+            let mut tx = conn.begin_immediate().await?;
 
-        let pool_options = sqlx::sqlite::SqliteConnectOptions::from_str(&db_path)?
-            .busy_timeout(std::time::Duration::from_secs(5))
-            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
-            .create_if_missing(true);
+            schema::open(&mut tx, Self::STRATEGY, options.codec).await?;
 
-        let pool = sqlx::SqlitePool::connect_with(pool_options).await?;
+            create_tables(&mut tx).await?;
 
-        let mut conn = pool.acquire().await?;
+            index::open(&mut tx).await?;
 
-        let mut tx = conn.begin_immediate().await?;
+            changes::open(&mut tx, options.change_log).await?;
 
-        sqlx::query(
-            "create table if not exists keys (
-            id integer primary key,
-            key blob not null,
-            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
-        )
-        ",
-        )
-        .execute(&mut *tx)
-        .await?;
+            tx.commit().await?;
+        }
 
-        sqlx::query(
-            "
-            create table if not exists vvalues (
-                id integer primary key,
-                key_id integer not null,
-                value blob not null,
-                inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+        Ok(Self {
+            pool,
+            codec: options.codec,
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            bucket: String::new(),
+            indexes: Indexes::default(),
+            tombstones: options.tombstones,
+            tombstone_retention: options.tombstone_retention,
+        })
+    }
 
-                foreign key(key_id) references keys(id) on delete cascade
-            )",
-        )
-        .execute(&mut *tx)
-        .await?;
+    fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
 
-        sqlx::query(
-            "
-            create unique index if not exists keys_key on keys (key);
-        ",
-        )
-        .execute(&mut *tx)
-        .await?;
+    fn codec(&self) -> Codec {
+        self.codec
+    }
 
-        sqlx::query(
-            "
-            create index if not exists vvalues_inserted_at on vvalues (inserted_at);
-        ",
-        )
-        .execute(&mut *tx)
-        .await?;
+    fn events(&self) -> &broadcast::Sender<Event> {
+        &self.events
+    }
 
-        sqlx::query(
-            "
-            create index if not exists vvalues_key_id on vvalues (key_id);
-        ",
-        )
-        .execute(&mut *tx)
-        .await?;
+    fn with_bucket(&self, bucket: &str) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+            ..self.clone()
+        }
+    }
 
-        tx.commit().await?;
+    fn bucket(&self) -> &str {
+        &self.bucket
+    }
 
-        Ok(Self { pool })
+    fn indexes(&self) -> &Indexes {
+        &self.indexes
     }
 
-    async fn read<K, V>(&self, key: &K) -> Result<Option<V>, Error>
+    async fn read<K, V>(&self, conn: &mut SqliteConnection, key: &K) -> Result<Option<V>, Error>
     where
         K: AsRef<[u8]> + ?Sized,
         V: DeserializeOwned,
     {
-        let mut conn = self.pool.acquire().await?;
-
-        let value_bytes: Option<(Vec<u8>,)> = sqlx::query_as(
+        let value_bytes: Option<(Option<Vec<u8>>,)> = sqlx::query_as(
             "
         select
             vvalues.value
         from keys
         inner join vvalues
             on vvalues.key_id = keys.id
-        where key = ?
-        order by vvalues.inserted_at desc
+        where bucket = ?
+        and key = ?
+        and (keys.expires_at is null or keys.expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+        order by vvalues.id desc
         limit 1
         ",
         )
+        .bind(&self.bucket)
         .bind(key.as_ref())
         .fetch_optional(&mut *conn)
         .await?;
 
-        if let Some((value_bytes,)) = value_bytes {
-            let value: V = ciborium::from_reader(&value_bytes[..])?;
+        if let Some((Some(value_bytes),)) = value_bytes {
+            let value: V = self.codec.deserialize(&value_bytes)?;
             Ok(Some(value))
         } else {
             Ok(None)
         }
     }
 
-    async fn write<K, V>(&self, key: &K, value: &V) -> Result<(), Error>
+    async fn write<K>(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &K,
+        value: &EncodedValue,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Revision, Error>
     where
         K: AsRef<[u8]> + ?Sized,
-        V: Serialize + ?Sized,
     {
-        let mut conn = self.pool.acquire().await?;
-
-        let mut tx = conn.begin_immediate().await?;
-
-        let mut value_bytes = vec![];
-        ciborium::into_writer(value, &mut value_bytes)?;
-
         let (key_id,): (i64,) = sqlx::query_as(
             "
-        insert into keys (key) values(?)
-        on conflict do update set key=excluded.key
+        insert into keys (bucket, key, expires_at) values(?, ?, ?)
+        on conflict do update set expires_at=excluded.expires_at
         returning id;
         ",
         )
+        .bind(&self.bucket)
         .bind(key.as_ref())
-        .fetch_one(&mut *tx)
+        .bind(expires_at.map(sqlite_timestamp))
+        .fetch_one(&mut *conn)
         .await?;
 
-        sqlx::query(
-            "insert into vvalues (key_id, value) values(?, ?);
+        let (value_id,): (i64,) = sqlx::query_as(
+            "insert into vvalues (key_id, value) values(?, ?)
+            returning id;
         ",
         )
         .bind(key_id)
-        .bind(value_bytes)
-        .execute(&mut *tx)
+        .bind(value)
+        .fetch_one(&mut *conn)
         .await?;
 
-        tx.commit().await?;
+        Ok(Revision(value_id))
+    }
 
-        Ok(())
+    async fn current<K>(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &K,
+    ) -> Result<Option<(Vec<u8>, Revision)>, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        let current: Option<(Option<Vec<u8>>, i64)> = sqlx::query_as(
+            "
+        select
+            vvalues.value,
+            vvalues.id
+        from keys
+        inner join vvalues
+            on vvalues.key_id = keys.id
+        where bucket = ?
+        and key = ?
+        and (keys.expires_at is null or keys.expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+        order by vvalues.id desc
+        limit 1
+        ",
+        )
+        .bind(&self.bucket)
+        .bind(key.as_ref())
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        if let Some((Some(value_bytes), value_id)) = current {
+            Ok(Some((value_bytes, Revision(value_id))))
+        } else {
+            Ok(None)
+        }
     }
 
-    async fn delete<K>(&self, key: &K) -> Result<(), Error>
+    async fn delete<K>(&self, conn: &mut SqliteConnection, key: &K) -> Result<(), Error>
     where
         K: AsRef<[u8]> + ?Sized,
     {
-        let mut conn = self.pool.acquire().await?;
+        if self.tombstones {
+            return self.append_tombstone(conn, key).await;
+        }
 
         sqlx::query(
             "
         delete from keys
-        where key = ?
+        where bucket = ?
+        and key = ?
         ",
         )
+        .bind(&self.bucket)
         .bind(key.as_ref())
         .execute(&mut *conn)
         .await?;
@@ -175,15 +223,199 @@ impl Storage for Append {
         Ok(())
     }
 
-    /// the distinct number of keys in the system
-    async fn keys_count(&self) -> Result<u64, Error> {
-        let mut conn = self.pool.acquire().await?;
+    async fn range<V>(
+        &self,
+        conn: &mut SqliteConnection,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: Option<u32>,
+    ) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        V: DeserializeOwned,
+    {
+        let (condition, binds) = range_condition("keys.key", start, end);
+
+        let sql = format!(
+            "
+            select
+                key,
+                value
+            from (
+                select
+                    keys.key,
+                    (
+                        select
+                            vvalues.value
+                        from vvalues
+                        where vvalues.key_id = keys.id
+                        order by vvalues.id desc
+                        limit 1
+                    ) as value
+                from keys
+                where bucket = ?
+            and {condition}
+                and (keys.expires_at is null or keys.expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+            )
+            where value is not null
+            order by key
+            limit ?
+            "
+        );
+
+        let mut query = sqlx::query_as(&sql).bind(&self.bucket);
+
+        for bind in binds {
+            query = query.bind(bind);
+        }
+
+        let rows: Vec<(Vec<u8>, Vec<u8>)> =
+            query.bind(sql_limit(limit)).fetch_all(&mut *conn).await?;
+
+        rows.into_iter()
+            .map(|(key, value_bytes)| Ok((key, self.codec.deserialize(&value_bytes)?)))
+            .collect()
+    }
+
+    async fn range_keys(
+        &self,
+        conn: &mut SqliteConnection,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: Option<u32>,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let (condition, binds) = range_condition("keys.key", start, end);
+
+        let sql = format!(
+            "
+            select
+                keys.key
+            from keys
+            where bucket = ?
+            and {condition}
+            and (keys.expires_at is null or keys.expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+            and (
+                select
+                    vvalues.value is not null
+                from vvalues
+                where vvalues.key_id = keys.id
+                order by vvalues.id desc
+                limit 1
+            )
+            order by keys.key
+            limit ?
+            "
+        );
+
+        let mut query = sqlx::query_as(&sql).bind(&self.bucket);
+
+        for bind in binds {
+            query = query.bind(bind);
+        }
+
+        let keys: Vec<Vec<u8>> = query
+            .bind(sql_limit(limit))
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|(key,)| key)
+            .collect();
+
+        Ok(keys)
+    }
 
+    async fn clear(&self, conn: &mut SqliteConnection) -> Result<(), Error> {
+        sqlx::query(
+            "
+            delete from keys
+            where bucket = ?
+            ",
+        )
+        .bind(&self.bucket)
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn purge_expired(&self, conn: &mut SqliteConnection) -> Result<u64, Error> {
+        // expired keys read as deleted, and the change log records
+        // the delete when they are purged, unless they were deleted already
+        if changes::exists(conn).await? {
+            let expired: Vec<(Vec<u8>,)> = sqlx::query_as(
+                "
+                select
+                    key
+                from keys
+                where bucket = ?
+                and expires_at <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+                and (
+                    select
+                        vvalues.value is not null
+                    from vvalues
+                    where vvalues.key_id = keys.id
+                    order by vvalues.id desc
+                    limit 1
+                )
+                ",
+            )
+            .bind(&self.bucket)
+            .fetch_all(&mut *conn)
+            .await?;
+
+            for (key,) in expired {
+                changes::record(conn, EventKind::Delete, &self.bucket, &key, None).await?;
+            }
+        }
+
+        sqlx::query(
+            "
+            delete from index_entries
+            where bucket = ?1
+            and key in (
+                select
+                    key
+                from keys
+                where bucket = ?1
+                and expires_at <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+            )
+            ",
+        )
+        .bind(&self.bucket)
+        .execute(&mut *conn)
+        .await?;
+
+        let result = sqlx::query(
+            "
+            delete from keys
+            where bucket = ?
+            and expires_at <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+            ",
+        )
+        .bind(&self.bucket)
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// the distinct number of keys in the system
+    async fn keys_count(&self, conn: &mut SqliteConnection) -> Result<u64, Error> {
         let (entries_count,): (u64,) = sqlx::query_as(
             "
             select count(*) from keys
+            where bucket = ?
+            and (keys.expires_at is null or keys.expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+            and (
+                select
+                    vvalues.value is not null
+                from vvalues
+                where vvalues.key_id = keys.id
+                order by vvalues.id desc
+                limit 1
+            )
             ",
         )
+        .bind(&self.bucket)
         .fetch_one(&mut *conn)
         .await?;
 
@@ -191,14 +423,23 @@ impl Storage for Append {
     }
 
     /// all distinct keys in the system
-    async fn keys(&self) -> Result<Vec<Vec<u8>>, Error> {
-        let mut conn = self.pool.acquire().await?;
-
+    async fn keys(&self, conn: &mut SqliteConnection) -> Result<Vec<Vec<u8>>, Error> {
         let keys: Vec<Vec<u8>> = sqlx::query_as(
             "
             select key from keys
+            where bucket = ?
+            and (keys.expires_at is null or keys.expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+            and (
+                select
+                    vvalues.value is not null
+                from vvalues
+                where vvalues.key_id = keys.id
+                order by vvalues.id desc
+                limit 1
+            )
             ",
         )
+        .bind(&self.bucket)
         .fetch_all(&mut *conn)
         .await?
         .into_iter()
@@ -207,12 +448,151 @@ impl Storage for Append {
 
         Ok(keys)
     }
+
+    /// the names of the buckets that have keys
+    async fn buckets(&self, conn: &mut SqliteConnection) -> Result<Vec<String>, Error> {
+        let buckets: Vec<String> = sqlx::query_as(
+            "
+            select distinct
+                bucket
+            from keys
+            order by bucket
+            ",
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|(bucket,)| bucket)
+        .collect();
+
+        Ok(buckets)
+    }
+
+    async fn collect_garbage(&self, retention: Retention) -> Result<GarbageCollected, Error> {
+        retention::collect(
+            &self.pool,
+            &self.bucket,
+            retention,
+            self.tombstone_retention,
+        )
+        .await
+    }
+
+    fn start_maintenance(&self, maintenance: Maintenance) -> mpsc::UnboundedSender<Command> {
+        let (commands, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(maintenance::run(self.clone(), maintenance, receiver));
+
+        commands
+    }
+}
+
+impl Append {
+    /// delete a key by appending a tombstone,
+    /// unless it is missing or already deleted
+    pub(crate) async fn append_tombstone<K>(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &K,
+    ) -> Result<(), Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        sqlx::query(
+            "
+        insert into vvalues (key_id, value)
+        select
+            id,
+            null
+        from keys
+        where bucket = ?
+        and key = ?
+        and (
+            select
+                vvalues.value
+            from vvalues
+            where vvalues.key_id = keys.id
+            order by vvalues.id desc
+            limit 1
+        ) is not null
+        ",
+        )
+        .bind(&self.bucket)
+        .bind(key.as_ref())
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+pub(crate) async fn create_tables(conn: &mut SqliteConnection) -> Result<(), Error> {
+    sqlx::query(
+        "create table if not exists keys (
+        id integer primary key,
+        bucket text not null default '',
+        key blob not null,
+        expires_at datetime,
+        inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+    )
+    ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "
+        create table if not exists vvalues (
+            -- autoincrement, so the ids of deleted rows are never reused
+            -- and each is a revision of one write
+            id integer primary key autoincrement,
+            key_id integer not null,
+            -- null marks a tombstone
+            value blob,
+            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+
+            foreign key(key_id) references keys(id) on delete cascade
+        )",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "
+        create unique index if not exists keys_key on keys (bucket, key);
+    ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "
+        create index if not exists vvalues_inserted_at on vvalues (inserted_at);
+    ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "
+        create index if not exists vvalues_key_id on vvalues (key_id);
+    ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Db;
+    use crate::storage::update_in_place::UpdateInPlace;
+    use crate::{
+        AsOf, Codec, Db, Element, Error, EventKind, Expiry, GarbageCollected, Maintenance, Order,
+        ReadOnlyDb, Retention, Synchronous, Tuple, TypedDb, Version,
+    };
+    use futures::TryStreamExt;
 
     #[tokio::test]
     async fn roundtrip() {
@@ -235,20 +615,14 @@ mod tests {
         let value: String = db.read("hello").await.unwrap().unwrap();
         assert_eq!(value, "world");
 
-        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
-
         db.write("hello", "joe").await.unwrap();
         let value: String = db.read("hello").await.unwrap().unwrap();
         assert_eq!(value, "joe");
 
-        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
-
         db.write("hello", "mike").await.unwrap();
         let value: String = db.read("hello").await.unwrap().unwrap();
         assert_eq!(value, "mike");
 
-        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
-
         db.write("hello", "robert").await.unwrap();
         let value: String = db.read("hello").await.unwrap().unwrap();
         assert_eq!(value, "robert");
@@ -261,83 +635,1768 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn collect_garbage_old_values() {
+    async fn back_to_back_writes() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        for i in 0..100 {
+            db.write("hello", &i).await.unwrap();
+            let value: i32 = db.read("hello").await.unwrap().unwrap();
+            assert_eq!(value, i);
+        }
+
+        let versions: Vec<Version<i32>> = db
+            .read_range("hello", .., Order::Descending)
+            .try_collect()
+            .await
+            .unwrap();
+        let values: Vec<i32> = versions.into_iter().map(|v| v.value.unwrap()).collect();
+        assert_eq!(values, (0..100).rev().collect::<Vec<_>>());
+
+        db.write("hello", &100).await.unwrap();
+        db.delete_keeping_history("hello").await.unwrap();
+        let value: Option<i32> = db.read("hello").await.unwrap();
+        assert_eq!(value, None);
+
+        db.write("hello", &101).await.unwrap();
+        db.collect_garbage().await.unwrap();
+        assert_eq!(db.entries_count().await.unwrap(), 1);
+        let value: i32 = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, 101);
+    }
+
+    #[tokio::test]
+    async fn clock_steps_backwards() {
         let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
 
         db.write("hello", "world").await.unwrap();
-        let value: String = db.read("hello").await.unwrap().unwrap();
-        assert_eq!(value, "world");
 
-        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        // as if the clock was ahead when "world" was written
+        sqlx::query("update vvalues set inserted_at = '2999-01-01 00:00:00.000'")
+            .execute(&db.storage.pool)
+            .await
+            .unwrap();
 
         db.write("hello", "joe").await.unwrap();
         let value: String = db.read("hello").await.unwrap().unwrap();
         assert_eq!(value, "joe");
 
-        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
-
-        db.write("hello", "mike").await.unwrap();
+        db.collect_garbage().await.unwrap();
+        assert_eq!(db.entries_count().await.unwrap(), 1);
         let value: String = db.read("hello").await.unwrap().unwrap();
-        assert_eq!(value, "mike");
+        assert_eq!(value, "joe");
+    }
 
-        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+    #[tokio::test]
+    async fn tombstone_deletes() {
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .tombstone_deletes()
+            .change_log()
+            .finish()
+            .await
+            .unwrap();
 
-        db.write("hello", "robert").await.unwrap();
-        let value: String = db.read("hello").await.unwrap().unwrap();
-        assert_eq!(value, "robert");
+        db.write("hello", "world").await.unwrap();
+        db.write("hello", "joe").await.unwrap();
+        db.write("other", "x").await.unwrap();
 
-        let entries_count = db.entries_count().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        let before_delete = chrono::Utc::now();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
 
-        assert_eq!(entries_count, 4);
+        db.delete("hello").await.unwrap();
+        // deleting a deleted key appends nothing
+        db.delete("hello").await.unwrap();
+        db.delete("missing").await.unwrap();
+
+        let value: Option<String> = db.read("hello").await.unwrap();
+        assert_eq!(value, None);
+        assert_eq!(db.keys().await.unwrap(), vec![b"other".to_vec()]);
+        assert_eq!(db.entries_count().await.unwrap(), 4);
+
+        let value: AsOf<String> = db.read_as_of("hello", before_delete).await.unwrap();
+        assert_eq!(value, AsOf::Value("joe".to_string()));
+        let value: AsOf<String> = db.read_as_of("hello", chrono::Utc::now()).await.unwrap();
+        assert_eq!(value, AsOf::Deleted);
+
+        let versions: Vec<Version<String>> = db
+            .read_range("hello", .., Order::Ascending)
+            .try_collect()
+            .await
+            .unwrap();
+        let values: Vec<Option<String>> = versions.into_iter().map(|v| v.value).collect();
+        assert_eq!(
+            values,
+            vec![Some("world".to_string()), Some("joe".to_string()), None]
+        );
+
+        let changes = db.changes(0, 10).await.unwrap();
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes[3].kind(), EventKind::Delete);
+
+        // batch and transactional deletes append tombstones too
+        db.delete_many(["other"]).await.unwrap();
+        db.write("again", "y").await.unwrap();
+        let mut tx = db.transaction().await.unwrap();
+        tx.delete("again").await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(db.keys_count().await.unwrap(), 0);
+        assert_eq!(db.entries_count().await.unwrap(), 7);
+
+        // writing a deleted key brings it back
+        db.write("hello", "mike").await.unwrap();
+        let value: Option<String> = db.read("hello").await.unwrap();
+        assert_eq!(value, Some("mike".to_string()));
 
         db.collect_garbage().await.unwrap();
+        assert_eq!(db.keys().await.unwrap(), vec![b"hello".to_vec()]);
+        assert_eq!(db.entries_count().await.unwrap(), 1);
+    }
 
-        let keys_count = db.keys_count().await.unwrap();
-        assert_eq!(keys_count, 1);
+    #[tokio::test]
+    async fn tombstone_retention() {
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .tombstone_deletes()
+            .tombstone_retention(std::time::Duration::from_secs(60 * 60))
+            .finish()
+            .await
+            .unwrap();
 
-        let value: String = db.read("hello").await.unwrap().unwrap();
+        db.write("hello", "world").await.unwrap();
+        db.write("hello", "joe").await.unwrap();
+        db.delete("hello").await.unwrap();
 
-        assert_eq!(value, "robert");
+        // the values were written two minutes before the delete
+        sqlx::query(
+            "
+            update vvalues
+            set inserted_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', '-2 minutes')
+            where value is not null
+            ",
+        )
+        .execute(&db.storage.pool)
+        .await
+        .unwrap();
+        let before_delete = chrono::Utc::now() - chrono::Duration::minutes(1);
 
-        let count = db.entries_count().await.unwrap();
+        // the tombstone and the history before it are kept
+        // until the tombstone is older than the retention
+        db.collect_garbage().await.unwrap();
+        assert_eq!(db.entries_count().await.unwrap(), 3);
+        let value: AsOf<String> = db.read_as_of("hello", before_delete).await.unwrap();
+        assert_eq!(value, AsOf::Value("joe".to_string()));
+        let value: AsOf<String> = db.read_as_of("hello", chrono::Utc::now()).await.unwrap();
+        assert_eq!(value, AsOf::Deleted);
+        let versions: Vec<Version<String>> = db
+            .read_range("hello", .., Order::Ascending)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(versions.len(), 3);
+
+        // as if the retention period had passed
+        sqlx::query("update vvalues set inserted_at = '2000-01-01 00:00:00.000'")
+            .execute(&db.storage.pool)
+            .await
+            .unwrap();
 
-        assert_eq!(count, 1);
+        db.collect_garbage().await.unwrap();
+        assert_eq!(db.entries_count().await.unwrap(), 0);
+        let value: AsOf<String> = db.read_as_of("hello", chrono::Utc::now()).await.unwrap();
+        assert_eq!(value, AsOf::Absent);
+    }
 
-        let keys_count = db.keys_count().await.unwrap();
-        assert_eq!(keys_count, 1);
+    #[tokio::test]
+    async fn retention() {
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .codec(Codec::Raw)
+            .finish()
+            .await
+            .unwrap();
+
+        for i in 0..10 {
+            db.write("a", &"x".repeat(i)).await.unwrap();
+        }
+        db.write("b", "only").await.unwrap();
+
+        let collected = db
+            .collect_garbage_with(Retention::latest().keep_last(3))
+            .await
+            .unwrap();
+        assert_eq!(
+            collected,
+            GarbageCollected {
+                versions: 7,
+                keys: 0,
+                bytes: (0..7).sum(),
+            }
+        );
+
+        let versions: Vec<Version<String>> = db
+            .read_range("a", .., Order::Ascending)
+            .try_collect()
+            .await
+            .unwrap();
+        let lengths: Vec<usize> = versions
+            .iter()
+            .map(|v| v.value.as_ref().unwrap().len())
+            .collect();
+        assert_eq!(lengths, vec![7, 8, 9]);
+
+        // nothing more to collect
+        let collected = db
+            .collect_garbage_with(Retention::latest().keep_last(3))
+            .await
+            .unwrap();
+        assert_eq!(collected, GarbageCollected::default());
+
+        // the versions of "a" were written 0, 1 and 2 days ago
+        for (days, i) in [(2, 7), (1, 8), (0, 9)] {
+            sqlx::query("update vvalues set inserted_at = ? where value = ?")
+                .bind(sqlite_timestamp(
+                    chrono::Utc::now() - chrono::Duration::days(days),
+                ))
+                .bind("x".repeat(i).into_bytes())
+                .execute(&db.storage.pool)
+                .await
+                .unwrap();
+        }
 
-        let keys = db.keys().await.unwrap();
-        assert_eq!(keys, vec![b"hello"]);
+        let collected = db
+            .collect_garbage_with(
+                Retention::latest().keep_newer_than(std::time::Duration::from_secs(36 * 60 * 60)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(collected.versions, 1);
+        assert_eq!(db.entries_count().await.unwrap(), 3);
+
+        let collected = db.collect_garbage().await.unwrap();
+        assert_eq!(collected.versions, 1);
+        assert_eq!(collected.bytes, 8);
+        let value: String = db.read("a").await.unwrap().unwrap();
+        assert_eq!(value.len(), 9);
     }
 
     #[tokio::test]
-    async fn deletes() {
+    async fn retention_daily() {
         let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
 
-        db.write("a", "b").await.unwrap();
+        let day = |days: i64, hours: i64| {
+            let midnight = chrono::Utc::now()
+                .date_naive()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+                .and_utc();
+            sqlite_timestamp(
+                midnight - chrono::Duration::days(days) + chrono::Duration::hours(hours),
+            )
+        };
 
-        db.write("hello", "world").await.unwrap();
-        let value: String = db.read("hello").await.unwrap().unwrap();
+        // two versions on each of three days, one of them more than a year ago
+        let inserted_at = [
+            day(400, 1),
+            day(400, 2),
+            day(2, 1),
+            day(2, 2),
+            day(1, 1),
+            day(1, 2),
+        ];
+
+        for (i, inserted_at) in inserted_at.iter().enumerate() {
+            db.write("a", &i).await.unwrap();
+            sqlx::query(
+                "update vvalues set inserted_at = ? where id = (select max(id) from vvalues)",
+            )
+            .bind(inserted_at)
+            .execute(&db.storage.pool)
+            .await
+            .unwrap();
+        }
+        db.write("a", &6).await.unwrap();
+
+        let collected = db
+            .collect_garbage_with(
+                Retention::latest()
+                    .keep_daily_for(std::time::Duration::from_secs(365 * 24 * 60 * 60)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(collected.versions, 4);
+
+        let versions: Vec<Version<usize>> = db
+            .read_range("a", .., Order::Ascending)
+            .try_collect()
+            .await
+            .unwrap();
+        let values: Vec<usize> = versions.into_iter().map(|v| v.value.unwrap()).collect();
+        assert_eq!(values, vec![3, 5, 6]);
+    }
 
-        assert_eq!(value, "world");
+    #[tokio::test]
+    async fn collect_garbage_in_batches() {
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .tombstone_deletes()
+            .finish()
+            .await
+            .unwrap();
+
+        let keys: Vec<String> = (0..2500).map(|i| format!("{i:04}")).collect();
+
+        db.write_many(keys.iter().map(|key| (key, 1)))
+            .await
+            .unwrap();
+        db.write_many(keys.iter().map(|key| (key, 2)))
+            .await
+            .unwrap();
+        db.delete_many(&keys[..10]).await.unwrap();
+
+        let collected = db.collect_garbage().await.unwrap();
+        assert_eq!(collected.versions, 2500 + 10 * 2);
+        assert_eq!(collected.keys, 10);
+        assert_eq!(db.entries_count().await.unwrap(), 2490);
+        assert_eq!(db.keys_count().await.unwrap(), 2490);
+    }
 
-        let keys_count = db.keys_count().await.unwrap();
-        assert_eq!(keys_count, 2);
+    #[tokio::test]
+    async fn maintenance_collects_garbage() {
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .maintenance(
+                Maintenance::every(std::time::Duration::from_secs(60 * 60))
+                    .collect_garbage(Some(Retention::latest().keep_last(2))),
+            )
+            .finish()
+            .await
+            .unwrap();
+        let other = db.bucket("other");
+
+        for i in 0..3 {
+            db.write("a", &i).await.unwrap();
+            other.write("b", &i).await.unwrap();
+        }
 
-        let entries_count = db.entries_count().await.unwrap();
-        assert_eq!(entries_count, 2);
+        let report = db.maintenance().unwrap().trigger().await.unwrap();
+        assert_eq!(report.garbage.versions, 2);
+        assert_eq!(db.entries_count().await.unwrap(), 2);
+        assert_eq!(other.entries_count().await.unwrap(), 2);
+    }
 
-        db.delete("hello").await.unwrap();
+    #[tokio::test]
+    async fn maintenance_pause_and_resume() {
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .maintenance(Maintenance::every(std::time::Duration::from_millis(20)))
+            .finish()
+            .await
+            .unwrap();
+        let maintenance = db.maintenance().unwrap();
 
-        assert!(db.read::<str, String>("hello").await.unwrap().is_none());
+        maintenance.pause().unwrap();
 
-        let keys_count = db.keys_count().await.unwrap();
-        assert_eq!(keys_count, 1);
+        db.write("a", &1).await.unwrap();
+        db.write("a", &2).await.unwrap();
 
-        let entries_count = db.entries_count().await.unwrap();
-        assert_eq!(entries_count, 1);
+        tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+        assert_eq!(db.entries_count().await.unwrap(), 2);
 
-        let keys = db.keys().await.unwrap();
-        assert_eq!(keys, vec![b"a"]);
+        maintenance.resume().unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+        assert_eq!(db.entries_count().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn maintenance_stops_with_the_last_handle() {
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .maintenance(Maintenance::every(std::time::Duration::from_secs(60 * 60)))
+            .finish()
+            .await
+            .unwrap();
+        let other = db.bucket("other");
+        let maintenance = db.maintenance().unwrap();
+
+        drop(db);
+        maintenance.trigger().await.unwrap();
+
+        drop(other);
+        assert!(matches!(
+            maintenance.trigger().await,
+            Err(Error::MaintenanceStopped)
+        ));
+        assert!(matches!(
+            maintenance.pause(),
+            Err(Error::MaintenanceStopped)
+        ));
+    }
+
+    #[tokio::test]
+    async fn backup_while_writing() {
+        let path =
+            std::env::temp_dir().join(format!("kvqlite-backup-append-{}.db", std::process::id()));
+        let backup = std::env::temp_dir().join(format!(
+            "kvqlite-backup-append-copy-{}.db",
+            std::process::id()
+        ));
+        let restored = std::env::temp_dir().join(format!(
+            "kvqlite-backup-append-restored-{}.db",
+            std::process::id()
+        ));
+
+        let db: Db<Append> = Db::builder().with_db_path(&path).finish().await.unwrap();
+
+        db.write("a", &1).await.unwrap();
+        db.write("a", &2).await.unwrap();
+
+        let writer = db.clone();
+        let writes = tokio::spawn(async move {
+            for i in 0..50 {
+                writer.write("b", &i).await.unwrap();
+            }
+        });
+
+        db.backup_to(&backup).await.unwrap();
+        writes.await.unwrap();
+
+        Db::<Append>::restore(&backup, &restored).await.unwrap();
+
+        let copy: Db<Append> = Db::builder()
+            .with_db_path(&restored)
+            .finish()
+            .await
+            .unwrap();
+        let value: i32 = copy.read("a").await.unwrap().unwrap();
+        assert_eq!(value, 2);
+        let versions: Vec<Version<i32>> = copy
+            .read_range("a", .., Order::Ascending)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(versions.len(), 2);
+
+        assert!(matches!(
+            Db::<UpdateInPlace>::restore(&backup, &restored).await,
+            Err(Error::StrategyMismatch(Strategy::Append))
+        ));
+
+        db.storage.pool.close().await;
+        copy.storage.pool.close().await;
+        for file in [&path, &backup, &restored] {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut file = file.clone().into_os_string();
+                file.push(suffix);
+                let _ = std::fs::remove_file(file);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn collect_garbage_old_values() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("hello", "world").await.unwrap();
+        let value: String = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, "world");
+
+        db.write("hello", "joe").await.unwrap();
+        let value: String = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, "joe");
+
+        db.write("hello", "mike").await.unwrap();
+        let value: String = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, "mike");
+
+        db.write("hello", "robert").await.unwrap();
+        let value: String = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, "robert");
+
+        let entries_count = db.entries_count().await.unwrap();
+
+        assert_eq!(entries_count, 4);
+
+        db.collect_garbage().await.unwrap();
+
+        let keys_count = db.keys_count().await.unwrap();
+        assert_eq!(keys_count, 1);
+
+        let value: String = db.read("hello").await.unwrap().unwrap();
+
+        assert_eq!(value, "robert");
+
+        let count = db.entries_count().await.unwrap();
+
+        assert_eq!(count, 1);
+
+        let keys_count = db.keys_count().await.unwrap();
+        assert_eq!(keys_count, 1);
+
+        let keys = db.keys().await.unwrap();
+        assert_eq!(keys, vec![b"hello"]);
+    }
+
+    #[tokio::test]
+    async fn deletes() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("a", "b").await.unwrap();
+
+        db.write("hello", "world").await.unwrap();
+        let value: String = db.read("hello").await.unwrap().unwrap();
+
+        assert_eq!(value, "world");
+
+        let keys_count = db.keys_count().await.unwrap();
+        assert_eq!(keys_count, 2);
+
+        let entries_count = db.entries_count().await.unwrap();
+        assert_eq!(entries_count, 2);
+
+        db.delete("hello").await.unwrap();
+
+        assert!(db.read::<str, String>("hello").await.unwrap().is_none());
+
+        let keys_count = db.keys_count().await.unwrap();
+        assert_eq!(keys_count, 1);
+
+        let entries_count = db.entries_count().await.unwrap();
+        assert_eq!(entries_count, 1);
+
+        let keys = db.keys().await.unwrap();
+        assert_eq!(keys, vec![b"a"]);
+    }
+
+    #[tokio::test]
+    async fn read_as_of() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        let before_writes = chrono::Utc::now();
+
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+
+        db.write("hello", "world").await.unwrap();
+
+        let after_world = chrono::Utc::now();
+
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+
+        db.write("hello", "joe").await.unwrap();
+
+        let after_joe = chrono::Utc::now();
+
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+
+        db.delete_keeping_history("hello").await.unwrap();
+
+        let after_delete = chrono::Utc::now();
+
+        let value: AsOf<String> = db.read_as_of("hello", before_writes).await.unwrap();
+        assert_eq!(value, AsOf::Absent);
+
+        let value: AsOf<String> = db.read_as_of("hello", after_world).await.unwrap();
+        assert_eq!(value, AsOf::Value("world".to_string()));
+
+        let value: AsOf<String> = db.read_as_of("hello", after_joe).await.unwrap();
+        assert_eq!(value, AsOf::Value("joe".to_string()));
+
+        let value: AsOf<String> = db.read_as_of("hello", after_delete).await.unwrap();
+        assert_eq!(value, AsOf::Deleted);
+
+        let value: AsOf<String> = db.read_as_of("nope", after_delete).await.unwrap();
+        assert_eq!(value, AsOf::Absent);
+    }
+
+    #[tokio::test]
+    async fn delete_keeping_history() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("a", "b").await.unwrap();
+        db.write("hello", "world").await.unwrap();
+
+        db.delete_keeping_history("hello").await.unwrap();
+
+        assert!(db.read::<str, String>("hello").await.unwrap().is_none());
+
+        let keys_count = db.keys_count().await.unwrap();
+        assert_eq!(keys_count, 1);
+
+        let entries_count = db.entries_count().await.unwrap();
+        assert_eq!(entries_count, 3);
+
+        let keys = db.keys().await.unwrap();
+        assert_eq!(keys, vec![b"a"]);
+
+        db.write("hello", "again").await.unwrap();
+        let value: String = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, "again");
+
+        let keys_count = db.keys_count().await.unwrap();
+        assert_eq!(keys_count, 2);
+    }
+
+    #[tokio::test]
+    async fn collect_garbage_tombstones() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("a", "b").await.unwrap();
+        db.write("hello", "world").await.unwrap();
+
+        db.delete_keeping_history("hello").await.unwrap();
+
+        db.collect_garbage().await.unwrap();
+
+        let entries_count = db.entries_count().await.unwrap();
+        assert_eq!(entries_count, 1);
+
+        let value: AsOf<String> = db.read_as_of("hello", chrono::Utc::now()).await.unwrap();
+        assert_eq!(value, AsOf::Absent);
+    }
+
+    #[tokio::test]
+    async fn read_range() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("hello", "world").await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+
+        let after_world = chrono::Utc::now();
+
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+
+        db.write("hello", "joe").await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+
+        db.write("hello", "mike").await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+
+        db.delete_keeping_history("hello").await.unwrap();
+
+        db.write("other", "value").await.unwrap();
+
+        let versions: Vec<Version<String>> = db
+            .read_range("hello", .., Order::Ascending)
+            .try_collect()
+            .await
+            .unwrap();
+
+        let values: Vec<Option<String>> = versions.into_iter().map(|v| v.value).collect();
+        assert_eq!(
+            values,
+            vec![
+                Some("world".to_string()),
+                Some("joe".to_string()),
+                Some("mike".to_string()),
+                None
+            ]
+        );
+
+        let versions: Vec<Version<String>> = db
+            .read_range("hello", after_world.., Order::Descending)
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert!(versions
+            .windows(2)
+            .all(|w| w[0].inserted_at >= w[1].inserted_at));
+
+        let values: Vec<Option<String>> = versions.into_iter().map(|v| v.value).collect();
+        assert_eq!(
+            values,
+            vec![None, Some("mike".to_string()), Some("joe".to_string())]
+        );
+
+        let versions: Vec<Version<String>> = db
+            .read_range("hello", ..after_world, Order::Ascending)
+            .try_collect()
+            .await
+            .unwrap();
+
+        let values: Vec<Option<String>> = versions.into_iter().map(|v| v.value).collect();
+        assert_eq!(values, vec![Some("world".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn range() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        for key in ["d", "a", "c", "b", "e"] {
+            db.write(key, &key.to_uppercase()).await.unwrap();
+        }
+
+        let entries: Vec<(Vec<u8>, String)> = db.range("b".."d").await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"b".to_vec(), "B".to_string()),
+                (b"c".to_vec(), "C".to_string())
+            ]
+        );
+
+        let entries: Vec<(Vec<u8>, String)> = db.range("b"..="d").await.unwrap();
+        let keys: Vec<Vec<u8>> = entries.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+
+        let entries: Vec<(Vec<u8>, String)> = db.range("c"..).await.unwrap();
+        let keys: Vec<Vec<u8>> = entries.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![b"c".to_vec(), b"d".to_vec(), b"e".to_vec()]);
+
+        let entries: Vec<(Vec<u8>, String)> = db.range::<&str, _, _>(..).await.unwrap();
+        assert_eq!(entries.len(), 5);
+
+        db.delete_keeping_history("d").await.unwrap();
+
+        let entries: Vec<(Vec<u8>, String)> = db.range("c"..).await.unwrap();
+        let keys: Vec<Vec<u8>> = entries.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![b"c".to_vec(), b"e".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn prefix() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("tenant/a/2", "a2").await.unwrap();
+        db.write("tenant/b/1", "b1").await.unwrap();
+        db.write("tenant/a/1", "a1").await.unwrap();
+        db.write("tenant/ab", "ab").await.unwrap();
+        db.write("other", "other").await.unwrap();
+        db.write(&[0xff_u8, 0xff], "high").await.unwrap();
+
+        let entries: Vec<(Vec<u8>, String)> = db.prefix("tenant/a/").await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"tenant/a/1".to_vec(), "a1".to_string()),
+                (b"tenant/a/2".to_vec(), "a2".to_string())
+            ]
+        );
+
+        let entries: Vec<(Vec<u8>, String)> = db.prefix(&[0xff_u8]).await.unwrap();
+        assert_eq!(entries, vec![(vec![0xff, 0xff], "high".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn streams() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        // more than one page
+        for i in (0..2500u32).rev() {
+            db.write(&i.to_be_bytes(), &i).await.unwrap();
+        }
+
+        let keys: Vec<Vec<u8>> = db.stream_keys().try_collect().await.unwrap();
+        let expected: Vec<Vec<u8>> = (0..2500u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(keys, expected);
+
+        let entries: Vec<(Vec<u8>, u32)> = db.stream_entries().try_collect().await.unwrap();
+        assert_eq!(entries.len(), 2500);
+        assert!(entries
+            .iter()
+            .all(|(key, value)| key[..] == value.to_be_bytes()));
+        assert!(entries.windows(2).all(|w| w[0].1 + 1 == w[1].1));
+    }
+
+    #[tokio::test]
+    async fn transactions() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("alice", &100).await.unwrap();
+        db.write("bob", &0).await.unwrap();
+
+        let mut tx = db.transaction().await.unwrap();
+        let alice: i32 = tx.read("alice").await.unwrap().unwrap();
+        let bob: i32 = tx.read("bob").await.unwrap().unwrap();
+        tx.write("alice", &(alice - 30)).await.unwrap();
+        tx.write("bob", &(bob + 30)).await.unwrap();
+
+        let balances: Vec<(Vec<u8>, i32)> = tx.range("alice"..="bob").await.unwrap();
+        assert_eq!(
+            balances,
+            vec![(b"alice".to_vec(), 70), (b"bob".to_vec(), 30)]
+        );
+
+        tx.commit().await.unwrap();
+
+        let alice: i32 = db.read("alice").await.unwrap().unwrap();
+        let bob: i32 = db.read("bob").await.unwrap().unwrap();
+        assert_eq!((alice, bob), (70, 30));
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.write("alice", &0).await.unwrap();
+        tx.delete("bob").await.unwrap();
+        assert!(tx.read::<str, i32>("bob").await.unwrap().is_none());
+        tx.rollback().await.unwrap();
+
+        let alice: i32 = db.read("alice").await.unwrap().unwrap();
+        let bob: i32 = db.read("bob").await.unwrap().unwrap();
+        assert_eq!((alice, bob), (70, 30));
+
+        {
+            let mut tx = db.transaction().await.unwrap();
+            tx.write("carol", &1).await.unwrap();
+            let entries: Vec<(Vec<u8>, i32)> = tx.prefix("c").await.unwrap();
+            assert_eq!(entries, vec![(b"carol".to_vec(), 1)]);
+        }
+
+        assert!(db.read::<str, i32>("carol").await.unwrap().is_none());
+        assert_eq!(db.keys_count().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn conditional_writes() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.insert_if_absent("hello", "world").await.unwrap();
+
+        assert!(matches!(
+            db.insert_if_absent("hello", "joe").await,
+            Err(Error::ConditionFailed)
+        ));
+        assert!(matches!(
+            db.update_if_present("nope", "joe").await,
+            Err(Error::ConditionFailed)
+        ));
+        assert!(db.read::<str, String>("nope").await.unwrap().is_none());
+
+        db.compare_and_swap("hello", Some("world"), "joe")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            db.compare_and_swap("hello", Some("world"), "mike").await,
+            Err(Error::ConditionFailed)
+        ));
+        assert!(matches!(
+            db.compare_and_swap("hello", None, "mike").await,
+            Err(Error::ConditionFailed)
+        ));
+        db.compare_and_swap("other", None, "value").await.unwrap();
+
+        let (value, revision): (String, _) = db.read_with_revision("hello").await.unwrap().unwrap();
+        assert_eq!(value, "joe");
+
+        let new_revision = db
+            .write_if_revision("hello", revision, "mike")
+            .await
+            .unwrap();
+        assert_ne!(new_revision, revision);
+
+        assert!(matches!(
+            db.write_if_revision("hello", revision, "robert").await,
+            Err(Error::ConditionFailed)
+        ));
+
+        db.update_if_present("hello", "robert").await.unwrap();
+
+        let value: String = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, "robert");
+    }
+
+    #[tokio::test]
+    async fn revisions_are_not_reused() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("hello", "one").await.unwrap();
+        let (_, revision): (String, _) = db.read_with_revision("hello").await.unwrap().unwrap();
+
+        db.delete("hello").await.unwrap();
+        db.write("hello", "two").await.unwrap();
+
+        assert!(matches!(
+            db.write_if_revision("hello", revision, "stale").await,
+            Err(Error::ConditionFailed)
+        ));
+        let value: String = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, "two");
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("keep", "forever").await.unwrap();
+        db.write_with_expiry(
+            "session",
+            "abc",
+            Expiry::After(std::time::Duration::from_millis(50)),
+        )
+        .await
+        .unwrap();
+        db.write_with_expiry(
+            "renewed",
+            "abc",
+            Expiry::After(std::time::Duration::from_millis(50)),
+        )
+        .await
+        .unwrap();
+        db.write_with_expiry("expired", "abc", Expiry::At(chrono::Utc::now()))
+            .await
+            .unwrap();
+
+        assert!(db.read::<str, String>("expired").await.unwrap().is_none());
+        assert!(db.read::<str, String>("session").await.unwrap().is_some());
+        assert_eq!(db.keys_count().await.unwrap(), 3);
+
+        db.write("renewed", "def").await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+
+        assert!(db.read::<str, String>("session").await.unwrap().is_none());
+        assert_eq!(db.keys_count().await.unwrap(), 2);
+        assert_eq!(db.keys().await.unwrap().len(), 2);
+
+        let entries: Vec<(Vec<u8>, String)> = db.prefix("").await.unwrap();
+        let keys: Vec<Vec<u8>> = entries.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![b"keep".to_vec(), b"renewed".to_vec()]);
+
+        let keys: Vec<Vec<u8>> = db.stream_keys().try_collect().await.unwrap();
+        assert_eq!(keys, vec![b"keep".to_vec(), b"renewed".to_vec()]);
+
+        assert_eq!(db.purge_expired().await.unwrap(), 2);
+        assert_eq!(db.purge_expired().await.unwrap(), 0);
+
+        db.insert_if_absent("session", "new").await.unwrap();
+        let value: String = db.read("session").await.unwrap().unwrap();
+        assert_eq!(value, "new");
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write_many((0..1000u32).map(|i| (i.to_be_bytes(), i)))
+            .await
+            .unwrap();
+
+        assert_eq!(db.keys_count().await.unwrap(), 1000);
+
+        let values: Vec<Option<u32>> = db
+            .read_many([7u32, 1000, 3].map(u32::to_be_bytes))
+            .await
+            .unwrap();
+        assert_eq!(values, vec![Some(7), None, Some(3)]);
+
+        db.delete_many((0..500u32).map(u32::to_be_bytes))
+            .await
+            .unwrap();
+
+        assert_eq!(db.keys_count().await.unwrap(), 500);
+
+        db.write_many([("a", "b"), ("c", "d")]).await.unwrap();
+        let values: Vec<Option<String>> = db.read_many(["c", "a"]).await.unwrap();
+        assert_eq!(values, vec![Some("d".to_string()), Some("b".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn codecs() {
+        for codec in [
+            Codec::Cbor,
+            Codec::Json,
+            Codec::MessagePack,
+            Codec::Postcard,
+        ] {
+            let db: Db<Append> = Db::builder()
+                .in_memory()
+                .codec(codec)
+                .finish()
+                .await
+                .unwrap();
+
+            db.write("hello", &("world", 1)).await.unwrap();
+            let value: (String, i32) = db.read("hello").await.unwrap().unwrap();
+            assert_eq!(value, ("world".to_string(), 1));
+
+            let entries: Vec<(Vec<u8>, (String, i32))> = db.prefix("h").await.unwrap();
+            assert_eq!(entries.len(), 1);
+        }
+
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .codec(Codec::Raw)
+            .finish()
+            .await
+            .unwrap();
+
+        db.write("hello", &b"world"[..]).await.unwrap();
+        let value: Vec<u8> = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, b"world");
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        let mut events = std::pin::pin!(db.watch("a"));
+
+        db.write("a", "b").await.unwrap();
+        db.write("other", "x").await.unwrap();
+        db.write("a", "c").await.unwrap();
+        db.delete("a").await.unwrap();
+        // deleting a missing key is not a change
+        db.delete("a").await.unwrap();
+        db.write("a", "d").await.unwrap();
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Put);
+        assert_eq!(event.key(), b"a");
+        assert_eq!(event.old_value::<String>().unwrap(), None);
+        assert_eq!(event.new_value::<String>().unwrap(), Some("b".to_string()));
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Put);
+        assert_eq!(event.old_value::<String>().unwrap(), Some("b".to_string()));
+        assert_eq!(event.new_value::<String>().unwrap(), Some("c".to_string()));
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Delete);
+        assert_eq!(event.old_value::<String>().unwrap(), Some("c".to_string()));
+        assert_eq!(event.new_value::<String>().unwrap(), None);
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Put);
+        assert_eq!(event.old_value::<String>().unwrap(), None);
+        assert_eq!(event.new_value::<String>().unwrap(), Some("d".to_string()));
+    }
+
+    #[tokio::test]
+    async fn watch_prefix() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        let mut events = std::pin::pin!(db.watch_prefix("config/"));
+
+        db.write("config/a", "1").await.unwrap();
+        db.write("other", "x").await.unwrap();
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.write("config/b", "rolled back").await.unwrap();
+        tx.rollback().await.unwrap();
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.write("config/c", "3").await.unwrap();
+        tx.delete("config/a").await.unwrap();
+        tx.commit().await.unwrap();
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.kind(), event.key()),
+            (EventKind::Put, &b"config/a"[..])
+        );
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.kind(), event.key()),
+            (EventKind::Put, &b"config/c"[..])
+        );
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.kind(), event.key()),
+            (EventKind::Delete, &b"config/a"[..])
+        );
+        assert_eq!(event.old_value::<String>().unwrap(), Some("1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn change_log() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+        assert!(matches!(db.changes(0, 10).await, Err(Error::NoChangeLog)));
+
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .change_log()
+            .finish()
+            .await
+            .unwrap();
+
+        db.write("a", "b").await.unwrap();
+        db.delete("a").await.unwrap();
+        // deleting a missing key is not a change
+        db.delete("a").await.unwrap();
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.write("c", "rolled back").await.unwrap();
+        tx.rollback().await.unwrap();
+
+        db.write_many([("c", "d"), ("e", "f")]).await.unwrap();
+
+        let changes = db.changes(0, 10).await.unwrap();
+        let summary: Vec<(u64, EventKind, &[u8], Option<String>)> = changes
+            .iter()
+            .map(|change| {
+                (
+                    change.seq(),
+                    change.kind(),
+                    change.key(),
+                    change.value().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, EventKind::Put, &b"a"[..], Some("b".to_string())),
+                (2, EventKind::Delete, &b"a"[..], None),
+                (3, EventKind::Put, &b"c"[..], Some("d".to_string())),
+                (4, EventKind::Put, &b"e"[..], Some("f".to_string())),
+            ]
+        );
+
+        let changes = db.changes(2, 1).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].seq(), 3);
+
+        assert_eq!(db.truncate_changes(3).await.unwrap(), 3);
+        db.write("g", "h").await.unwrap();
+        let seqs: Vec<u64> = db
+            .changes(0, 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|change| change.seq())
+            .collect();
+        assert_eq!(seqs, vec![4, 5]);
+
+        // purging an expired key records its delete
+        let past = Expiry::At(chrono::Utc::now() - chrono::Duration::seconds(1));
+        db.write_with_expiry("i", "j", past).await.unwrap();
+        assert_eq!(db.purge_expired().await.unwrap(), 1);
+        let changes = db.changes(5, 10).await.unwrap();
+        let summary: Vec<(EventKind, &[u8])> = changes
+            .iter()
+            .map(|change| (change.kind(), change.key()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(EventKind::Put, &b"i"[..]), (EventKind::Delete, &b"i"[..]),]
+        );
+    }
+
+    #[tokio::test]
+    async fn buckets() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+        let users = db.bucket("users");
+        let teams = db.bucket("teams");
+
+        db.write("a", "default").await.unwrap();
+        users.write("a", "user a").await.unwrap();
+        users.write("b", "user b").await.unwrap();
+        teams.write("a", "team a").await.unwrap();
+
+        let value: String = db.read("a").await.unwrap().unwrap();
+        assert_eq!(value, "default");
+        let value: String = users.read("a").await.unwrap().unwrap();
+        assert_eq!(value, "user a");
+        let value: String = db.bucket("teams").read("a").await.unwrap().unwrap();
+        assert_eq!(value, "team a");
+
+        assert_eq!(db.keys_count().await.unwrap(), 1);
+        assert_eq!(users.keys_count().await.unwrap(), 2);
+        assert_eq!(
+            users.keys().await.unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+
+        let entries: Vec<(Vec<u8>, String)> = users.prefix("").await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), "user a".to_string()),
+                (b"b".to_vec(), "user b".to_string())
+            ]
+        );
+
+        users.write("a", "newer").await.unwrap();
+        db.write("a", "newer").await.unwrap();
+        users.collect_garbage().await.unwrap();
+        assert_eq!(users.entries_count().await.unwrap(), 2);
+        assert_eq!(db.entries_count().await.unwrap(), 2);
+
+        teams.delete("a").await.unwrap();
+        let value: Option<String> = users.read("a").await.unwrap();
+        assert!(value.is_some());
+
+        db.drop_bucket("users").await.unwrap();
+        assert_eq!(users.keys_count().await.unwrap(), 0);
+        let value: Option<String> = db.read("a").await.unwrap();
+        assert!(value.is_some());
+    }
+
+    #[tokio::test]
+    async fn watch_bucket() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+        let users = db.bucket("users");
+
+        let mut events = std::pin::pin!(users.watch_prefix(""));
+
+        db.write("a", "default").await.unwrap();
+        users.write("a", "user a").await.unwrap();
+        db.drop_bucket("users").await.unwrap();
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!((event.bucket(), event.kind()), ("users", EventKind::Put));
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!((event.bucket(), event.kind()), ("users", EventKind::Delete));
+        assert_eq!(
+            event.old_value::<String>().unwrap(),
+            Some("user a".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn typed() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        let scores: TypedDb<i64, String, Append> = db.bucket("scores").typed();
+        for score in [256, -3, 10, 2] {
+            scores
+                .write(&score, &format!("score {score}"))
+                .await
+                .unwrap();
+        }
+        assert_eq!(scores.keys().await.unwrap(), vec![-3, 2, 10, 256]);
+        assert_eq!(
+            scores.range(0..100).await.unwrap(),
+            vec![(2, "score 2".to_string()), (10, "score 10".to_string())]
+        );
+
+        let teams: TypedDb<(String, u32), bool, Append> = db.bucket("teams").typed();
+        teams.write(&("b".to_string(), 1), &true).await.unwrap();
+        teams.write(&("a".to_string(), 10), &false).await.unwrap();
+        teams.write(&("a".to_string(), 9), &true).await.unwrap();
+        assert_eq!(teams.read(&("a".to_string(), 9)).await.unwrap(), Some(true));
+
+        let entries: Vec<((String, u32), bool)> =
+            teams.stream_entries().try_collect().await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (("a".to_string(), 9), true),
+                (("a".to_string(), 10), false),
+                (("b".to_string(), 1), true)
+            ]
+        );
+
+        teams.delete(&("b".to_string(), 1)).await.unwrap();
+        assert_eq!(teams.keys_count().await.unwrap(), 2);
+
+        // string keys are the same typed and untyped
+        let names: TypedDb<String, u32, Append> = db.typed();
+        names.write(&"joe".to_string(), &1).await.unwrap();
+        let value: u32 = db.read("joe").await.unwrap().unwrap();
+        assert_eq!(value, 1);
+    }
+
+    #[tokio::test]
+    async fn tuple_keys() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        let user = |id: i64, field: &str| Tuple::new().push("user").push(id).push(field);
+        db.write(&user(10, "name").pack(), "joe").await.unwrap();
+        db.write(&user(-5, "name").pack(), "mike").await.unwrap();
+        db.write(&user(10, "age").pack(), "40").await.unwrap();
+        db.write(&Tuple::new().push("team").push(1).pack(), "a")
+            .await
+            .unwrap();
+
+        let users: Vec<Tuple> = db
+            .prefix::<_, String>(&Tuple::new().push("user").pack())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| Tuple::unpack(&key).unwrap())
+            .collect();
+        assert_eq!(
+            users,
+            vec![user(-5, "name"), user(10, "age"), user(10, "name")]
+        );
+
+        let typed: TypedDb<Tuple, String, Append> = db.typed();
+        let fields = typed.range(user(10, "")..user(11, "")).await.unwrap();
+        assert_eq!(
+            fields,
+            vec![
+                (user(10, "age"), "40".to_string()),
+                (user(10, "name"), "joe".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn indexes() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        let user = |email: &str, age: i64, city: &str| serde_json::json!({"email": email, "age": age, "address": {"city": city}});
+
+        db.write("joe", &user("joe@example", 40, "paris"))
+            .await
+            .unwrap();
+        db.write("mike", &user("mike@example", 30, "rome"))
+            .await
+            .unwrap();
+
+        // indexes are built from existing values when registered
+        db.create_index("email", |user: &serde_json::Value| {
+            user["email"].as_str().map(str::to_string)
+        })
+        .await
+        .unwrap();
+        db.create_path_index("age", "age").await.unwrap();
+        db.create_path_index("city", "address.city").await.unwrap();
+
+        db.write("ann", &user("ann@example", 35, "paris"))
+            .await
+            .unwrap();
+        // not a user, so not indexed by email
+        db.write("other", "value").await.unwrap();
+
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"mike@example".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            found,
+            vec![(b"mike".to_vec(), user("mike@example", 30, "rome"))]
+        );
+
+        let found: Vec<(Vec<u8>, serde_json::Value)> =
+            db.index_range("age", Element::Int(31)..).await.unwrap();
+        let keys: Vec<&[u8]> = found.iter().map(|(key, _)| &key[..]).collect();
+        assert_eq!(keys, vec![&b"ann"[..], b"joe"]);
+
+        let found: Vec<(Vec<u8>, serde_json::Value)> =
+            db.index_get("city", &Element::from("paris")).await.unwrap();
+        let keys: Vec<&[u8]> = found.iter().map(|(key, _)| &key[..]).collect();
+        assert_eq!(keys, vec![&b"ann"[..], b"joe"]);
+
+        db.write("joe", &user("joe@new.example", 41, "paris"))
+            .await
+            .unwrap();
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"joe@example".to_string())
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"joe@new.example".to_string())
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.delete("mike").await.unwrap();
+        tx.rollback().await.unwrap();
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"mike@example".to_string())
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+
+        db.delete("mike").await.unwrap();
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"mike@example".to_string())
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+
+        db.delete_keeping_history("joe").await.unwrap();
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"joe@new.example".to_string())
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+
+        db.drop_index("email").await.unwrap();
+        let found: Result<Vec<(Vec<u8>, serde_json::Value)>, Error> =
+            db.index_get("email", &"ann@example".to_string()).await;
+        assert!(matches!(found, Err(Error::UnknownIndex(_))));
+
+        // indexes belong to a bucket
+        let found: Result<Vec<(Vec<u8>, serde_json::Value)>, Error> =
+            db.bucket("other").index_get("age", &Element::Int(35)).await;
+        assert!(matches!(found, Err(Error::UnknownIndex(_))));
+    }
+
+    #[tokio::test]
+    async fn in_memory_is_shared_by_connections() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("a", "b").await.unwrap();
+
+        let mut conns = vec![];
+        for _ in 0..3 {
+            conns.push(db.storage.pool.acquire().await.unwrap());
+        }
+
+        for conn in &mut conns {
+            let (count,): (i64,) = sqlx::query_as("select count(*) from keys")
+                .fetch_one(&mut **conn)
+                .await
+                .unwrap();
+            assert_eq!(count, 1);
+        }
+
+        // each in-memory database is separate
+        let other: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+        assert_eq!(other.keys_count().await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_connections() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        let mut tasks = vec![];
+
+        for task in 0..16 {
+            let db = db.clone();
+            tasks.push(tokio::spawn(async move {
+                for i in 0..25 {
+                    let key = format!("{task}-{i}");
+                    db.write(&key, &i).await.unwrap();
+                    let value: Option<i32> = db.read(&key).await.unwrap();
+                    assert_eq!(value, Some(i));
+
+                    let mut tx = db.transaction().await.unwrap();
+                    let value: Option<i32> = tx.read(&key).await.unwrap();
+                    tx.write(&format!("{key}-copy"), &value).await.unwrap();
+                    tx.commit().await.unwrap();
+                }
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert!(db.storage.pool.size() > 1);
+        assert_eq!(db.keys_count().await.unwrap(), 16 * 25 * 2);
+    }
+
+    #[tokio::test]
+    async fn connection_options() {
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .max_connections(2)
+            .busy_timeout(std::time::Duration::from_millis(250))
+            .synchronous(Synchronous::Normal)
+            .page_cache_size(-4000)
+            .pragma("user_version", "7")
+            .finish()
+            .await
+            .unwrap();
+
+        db.write("a", "1").await.unwrap();
+
+        let mut conn = db.storage.pool.acquire().await.unwrap();
+
+        for (pragma, expected) in [
+            ("busy_timeout", 250),
+            ("synchronous", 1),
+            ("cache_size", -4000),
+            ("user_version", 7),
+        ] {
+            let (value,): (i64,) = sqlx::query_as(&format!("pragma {pragma}"))
+                .fetch_one(&mut *conn)
+                .await
+                .unwrap();
+            assert_eq!(value, expected, "{pragma}");
+        }
+
+        assert_eq!(db.storage.pool.options().get_max_connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn read_only() {
+        let path = std::env::temp_dir().join(format!(
+            "kvqlite-read-only-append-{}.db",
+            std::process::id()
+        ));
+
+        // a read-only handle does not create the database
+        assert!(Db::<Append>::builder()
+            .with_db_path(&path)
+            .read_only()
+            .finish()
+            .await
+            .is_err());
+
+        let writer: Db<Append> = Db::builder().with_db_path(&path).finish().await.unwrap();
+        writer.write("a", "1").await.unwrap();
+        writer.write("b", "2").await.unwrap();
+
+        let reader: ReadOnlyDb<Append> = Db::builder()
+            .with_db_path(&path)
+            .read_only()
+            .finish()
+            .await
+            .unwrap();
+
+        let value: Option<String> = reader.read("a").await.unwrap();
+        assert_eq!(value, Some("1".to_string()));
+        assert_eq!(
+            reader.keys().await.unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(reader.entries_count().await.unwrap(), 2);
+
+        writer.write("a", "3").await.unwrap();
+        let value: Option<String> = reader.read("a").await.unwrap();
+        assert_eq!(value, Some("3".to_string()));
+
+        // the connections themselves are read-only
+        let result = sqlx::query("delete from keys")
+            .execute(&reader.db.storage.pool)
+            .await;
+        assert!(result.is_err());
+
+        writer.storage.pool.close().await;
+        reader.db.storage.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_old_formats() {
+        let path =
+            std::env::temp_dir().join(format!("kvqlite-migrate-append-{}.db", std::process::id()));
+
+        // the layout from before the metadata table
+        let pool = SqlitePool::connect_with(
+            sqlx::sqlite::SqliteConnectOptions::new()
+                .filename(&path)
+                .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+
+        sqlx::query(
+            "
+            create table keys (
+                id integer primary key,
+                key blob not null,
+                inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+            )
+            ",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        sqlx::query(
+            "
+            create table vvalues (
+                id integer primary key,
+                key_id integer not null,
+                value blob not null,
+                inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+
+                foreign key(key_id) references keys(id) on delete cascade
+            )
+            ",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        sqlx::query("create unique index keys_key on keys (key)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        sqlx::query("insert into keys (id, key) values(1, ?)")
+            .bind(b"a".as_slice())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        for (value, inserted_at) in [
+            ("1", "2024-01-01 00:00:00.000"),
+            ("2", "2024-01-02 00:00:00.000"),
+        ] {
+            sqlx::query("insert into vvalues (key_id, value, inserted_at) values(1, ?, ?)")
+                .bind(Codec::Cbor.serialize(value).unwrap())
+                .bind(inserted_at)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        pool.close().await;
+
+        assert!(matches!(
+            Db::<Append>::builder()
+                .with_db_path(&path)
+                .read_only()
+                .finish()
+                .await,
+            Err(Error::MigrationNeeded(0))
+        ));
+
+        // the strategy is worked out from the tables
+        assert!(matches!(
+            Db::<UpdateInPlace>::builder()
+                .with_db_path(&path)
+                .finish()
+                .await,
+            Err(Error::StrategyMismatch(Strategy::Append))
+        ));
+
+        let db: Db<Append> = Db::builder().with_db_path(&path).finish().await.unwrap();
+
+        let value: Option<String> = db.read("a").await.unwrap();
+        assert_eq!(value, Some("2".to_string()));
+        assert_eq!(db.entries_count().await.unwrap(), 2);
+
+        db.bucket("other").write("a", "3").await.unwrap();
+        db.delete_keeping_history("a").await.unwrap();
+        let value: Option<String> = db.read("a").await.unwrap();
+        assert_eq!(value, None);
+        assert_eq!(db.entries_count().await.unwrap(), 3);
+
+        let meta: Vec<(String, String)> =
+            sqlx::query_as("select name, value from meta order by name")
+                .fetch_all(&db.storage.pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            meta,
+            vec![
+                ("codec".to_string(), "cbor".to_string()),
+                ("format_version".to_string(), "2".to_string()),
+                ("strategy".to_string(), "append".to_string()),
+            ]
+        );
+
+        // a file from a newer version of kvqlite
+        sqlx::query("update meta set value = '99' where name = 'format_version'")
+            .execute(&db.storage.pool)
+            .await
+            .unwrap();
+        db.storage.pool.close().await;
+
+        assert!(matches!(
+            Db::<Append>::builder().with_db_path(&path).finish().await,
+            Err(Error::UnsupportedFormatVersion(99))
+        ));
+
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn into_update_in_place() {
+        let db: Db<Append> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("a", "1").await.unwrap();
+        db.write("a", "2").await.unwrap();
+        db.write("b", "1").await.unwrap();
+        db.delete_keeping_history("b").await.unwrap();
+        db.bucket("other").write("a", "3").await.unwrap();
+
+        let db = db.into_update_in_place().await.unwrap();
+
+        let value: Option<String> = db.read("a").await.unwrap();
+        assert_eq!(value, Some("2".to_string()));
+        let value: Option<String> = db.read("b").await.unwrap();
+        assert_eq!(value, None);
+        let value: Option<String> = db.bucket("other").read("a").await.unwrap();
+        assert_eq!(value, Some("3".to_string()));
+        assert_eq!(db.keys().await.unwrap(), vec![b"a".to_vec()]);
+
+        db.write("a", "4").await.unwrap();
+        let value: Option<String> = db.read("a").await.unwrap();
+        assert_eq!(value, Some("4".to_string()));
+
+        let (tables,): (i64,) =
+            sqlx::query_as("select count(*) from sqlite_master where name in ('keys', 'vvalues')")
+                .fetch_one(&db.storage.pool)
+                .await
+                .unwrap();
+        assert_eq!(tables, 0);
+    }
+
+    #[tokio::test]
+    async fn strategy_mismatch() {
+        let path = std::env::temp_dir().join(format!(
+            "kvqlite-strategy-mismatch-append-{}.db",
+            std::process::id()
+        ));
+
+        let db: Db<Append> = Db::builder().with_db_path(&path).finish().await.unwrap();
+        db.write("a", "1").await.unwrap();
+
+        assert!(matches!(
+            Db::<UpdateInPlace>::builder()
+                .with_db_path(&path)
+                .finish()
+                .await,
+            Err(Error::StrategyMismatch(Strategy::Append))
+        ));
+        assert!(matches!(
+            Db::<UpdateInPlace>::builder()
+                .with_db_path(&path)
+                .read_only()
+                .finish()
+                .await,
+            Err(Error::StrategyMismatch(Strategy::Append))
+        ));
+
+        // the failed open left the file as it was
+        let (kvs,): (i64,) =
+            sqlx::query_as("select count(*) from sqlite_master where name = 'kvs'")
+                .fetch_one(&db.storage.pool)
+                .await
+                .unwrap();
+        assert_eq!(kvs, 0);
+
+        db.storage.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn json_is_queryable() {
+        let db: Db<Append> = Db::builder()
+            .in_memory()
+            .codec(Codec::Json)
+            .finish()
+            .await
+            .unwrap();
+
+        db.write("joe", &serde_json::json!({"name": "joe", "age": 42}))
+            .await
+            .unwrap();
+        db.write("mike", &serde_json::json!({"name": "mike", "age": 30}))
+            .await
+            .unwrap();
+
+        let names: Vec<(String,)> = sqlx::query_as(
+            "
+            select
+                json_extract(value, '$.name')
+            from vvalues
+            where json_extract(value, '$.age') > 40
+            ",
+        )
+        .fetch_all(&db.storage.pool)
+        .await
+        .unwrap();
+
+        assert_eq!(names, vec![("joe".to_string(),)]);
     }
 }
@@ -1,6 +1,18 @@
-use crate::{Error, Options};
+use crate::codec::EncodedValue;
+use crate::index::Indexes;
+use crate::maintenance::{Command, Maintenance};
+use crate::schema::Strategy;
+use crate::{
+    Codec, Error, Event, GarbageCollected, JournalMode, Options, Retention, Revision, Synchronous,
+};
+use chrono::{DateTime, Utc};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::ops::Bound;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{broadcast, mpsc};
 
 pub mod append;
 pub mod update_in_place;
@@ -9,32 +21,286 @@ pub(crate) mod private {
     pub trait Sealed {}
 }
 
-pub trait Storage: private::Sealed {
+pub trait Storage: private::Sealed + Clone {
+    /// how the storage lays out key/values in the database
+    const STRATEGY: Strategy;
+
     #[allow(async_fn_in_trait)]
     async fn open(options: Options) -> Result<Self, Error>
     where
         Self: Sized;
 
+    fn pool(&self) -> &SqlitePool;
+
+    fn codec(&self) -> Codec;
+
+    /// where committed changes are sent for watchers
+    fn events(&self) -> &broadcast::Sender<Event>;
+
+    /// a handle to the same database for the keys in `bucket`
+    fn with_bucket(&self, bucket: &str) -> Self;
+
+    /// the bucket this handle reads and writes,
+    /// where the empty string is the default bucket
+    fn bucket(&self) -> &str;
+
+    /// the secondary indexes registered with the database
+    fn indexes(&self) -> &Indexes;
+
     #[allow(async_fn_in_trait)]
-    async fn read<K, V>(&self, key: &K) -> Result<Option<V>, Error>
+    async fn read<K, V>(&self, conn: &mut SqliteConnection, key: &K) -> Result<Option<V>, Error>
     where
         K: AsRef<[u8]> + ?Sized,
         V: DeserializeOwned;
 
     #[allow(async_fn_in_trait)]
-    async fn write<K, V>(&self, key: &K, value: &V) -> Result<(), Error>
+    async fn write<K>(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &K,
+        value: &EncodedValue,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Revision, Error>
     where
-        K: AsRef<[u8]> + ?Sized,
-        V: Serialize + ?Sized;
+        K: AsRef<[u8]> + ?Sized;
+
+    /// the current serialized value of a key and its revision
+    #[allow(async_fn_in_trait)]
+    async fn current<K>(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &K,
+    ) -> Result<Option<(Vec<u8>, Revision)>, Error>
+    where
+        K: AsRef<[u8]> + ?Sized;
 
     #[allow(async_fn_in_trait)]
-    async fn delete<K>(&self, key: &K) -> Result<(), Error>
+    async fn delete<K>(&self, conn: &mut SqliteConnection, key: &K) -> Result<(), Error>
     where
         K: AsRef<[u8]> + ?Sized;
 
+    /// key/values with keys in the given range, in key order,
+    /// up to `limit` of them
     #[allow(async_fn_in_trait)]
-    async fn keys(&self) -> Result<Vec<Vec<u8>>, Error>;
+    async fn range<V>(
+        &self,
+        conn: &mut SqliteConnection,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: Option<u32>,
+    ) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        V: DeserializeOwned;
 
+    /// keys in the given range, in key order,
+    /// up to `limit` of them
     #[allow(async_fn_in_trait)]
-    async fn keys_count(&self) -> Result<u64, Error>;
+    async fn range_keys(
+        &self,
+        conn: &mut SqliteConnection,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: Option<u32>,
+    ) -> Result<Vec<Vec<u8>>, Error>;
+
+    /// delete every key in the bucket
+    #[allow(async_fn_in_trait)]
+    async fn clear(&self, conn: &mut SqliteConnection) -> Result<(), Error>;
+
+    /// delete expired keys, returning how many were deleted
+    #[allow(async_fn_in_trait)]
+    async fn purge_expired(&self, conn: &mut SqliteConnection) -> Result<u64, Error>;
+
+    #[allow(async_fn_in_trait)]
+    async fn keys(&self, conn: &mut SqliteConnection) -> Result<Vec<Vec<u8>>, Error>;
+
+    #[allow(async_fn_in_trait)]
+    async fn keys_count(&self, conn: &mut SqliteConnection) -> Result<u64, Error>;
+
+    /// the names of the buckets that have keys
+    #[allow(async_fn_in_trait)]
+    async fn buckets(&self, conn: &mut SqliteConnection) -> Result<Vec<String>, Error>;
+
+    /// delete the old versions in the bucket that `retention` doesn't keep,
+    /// for strategies that keep old versions
+    #[allow(async_fn_in_trait)]
+    async fn collect_garbage(&self, retention: Retention) -> Result<GarbageCollected, Error>;
+
+    /// spawn a task that maintains the database until every clone of the
+    /// returned sender is dropped
+    fn start_maintenance(&self, maintenance: Maintenance) -> mpsc::UnboundedSender<Command>;
+}
+
+/// how many events a watcher can fall behind by before it misses some
+pub(crate) const EVENTS_CAPACITY: usize = 1024;
+
+/// open a pool of connections to the database `options` describes
+pub(crate) async fn connect(options: &Options) -> Result<SqlitePool, Error> {
+    let mut pool_options = SqlitePoolOptions::new();
+
+    let mut connect_options = if options.in_memory {
+        // a named memdb database is shared by every connection that opens
+        // its name, and uses sqlite's usual locking between them.
+        // it is freed when its last connection closes, so the pool keeps
+        // one connection open for as long as the pool exists.
+        static IN_MEMORY_SEQ: AtomicU64 = AtomicU64::new(0);
+
+        let name = format!(
+            "/kvqlite-{}-{}",
+            std::process::id(),
+            IN_MEMORY_SEQ.fetch_add(1, Ordering::Relaxed)
+        );
+
+        pool_options = pool_options
+            .min_connections(options.min_connections.unwrap_or(0).max(1))
+            .idle_timeout(None)
+            .max_lifetime(None);
+
+        SqliteConnectOptions::new().filename(name).vfs("memdb")
+    } else {
+        let db_path = if let Some(p) = &options.db_path {
+            p.to_str().unwrap().to_string()
+        } else {
+            "sqlite://kvqlite.db".to_string()
+        };
+
+        if let Some(min_connections) = options.min_connections {
+            pool_options = pool_options.min_connections(min_connections);
+        }
+
+        SqliteConnectOptions::from_str(&db_path)?
+    };
+
+    if options.read_only {
+        connect_options = connect_options.read_only(true);
+    }
+
+    if let Some(max_connections) = options.max_connections {
+        pool_options = pool_options.max_connections(max_connections);
+    }
+
+    connect_options = connect_options
+        .busy_timeout(
+            options
+                .busy_timeout
+                .unwrap_or(std::time::Duration::from_secs(5)),
+        )
+        .create_if_missing(!options.read_only);
+
+    // memdb databases can't use WAL, and read-only connections
+    // can't change the journal mode
+    let journal_mode = if options.in_memory || options.read_only {
+        options.journal_mode
+    } else {
+        Some(options.journal_mode.unwrap_or(JournalMode::Wal))
+    };
+
+    if let Some(journal_mode) = journal_mode {
+        connect_options = connect_options.journal_mode(match journal_mode {
+            JournalMode::Delete => SqliteJournalMode::Delete,
+            JournalMode::Truncate => SqliteJournalMode::Truncate,
+            JournalMode::Persist => SqliteJournalMode::Persist,
+            JournalMode::Memory => SqliteJournalMode::Memory,
+            JournalMode::Wal => SqliteJournalMode::Wal,
+            JournalMode::Off => SqliteJournalMode::Off,
+        });
+    }
+
+    if let Some(synchronous) = options.synchronous {
+        connect_options = connect_options.synchronous(match synchronous {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        });
+    }
+
+    if let Some(page_cache_size) = options.page_cache_size {
+        connect_options = connect_options.pragma("cache_size", page_cache_size.to_string());
+    }
+
+    if let Some(mmap_size) = options.mmap_size {
+        connect_options = connect_options.pragma("mmap_size", mmap_size.to_string());
+    }
+
+    for (key, value) in &options.pragmas {
+        connect_options = connect_options.pragma(key.clone(), value.clone());
+    }
+
+    let pool = pool_options.connect_with(connect_options).await?;
+
+    Ok(pool)
+}
+
+/// a sql condition on `column` for the given bounds,
+/// and the values to bind for it, in order
+pub(crate) fn range_condition<'a>(
+    column: &str,
+    start: Bound<&'a [u8]>,
+    end: Bound<&'a [u8]>,
+) -> (String, Vec<&'a [u8]>) {
+    let mut conditions = vec![];
+    let mut binds = vec![];
+
+    match start {
+        Bound::Included(start) => {
+            conditions.push(format!("{column} >= ?"));
+            binds.push(start);
+        }
+        Bound::Excluded(start) => {
+            conditions.push(format!("{column} > ?"));
+            binds.push(start);
+        }
+        Bound::Unbounded => (),
+    }
+
+    match end {
+        Bound::Included(end) => {
+            conditions.push(format!("{column} <= ?"));
+            binds.push(end);
+        }
+        Bound::Excluded(end) => {
+            conditions.push(format!("{column} < ?"));
+            binds.push(end);
+        }
+        Bound::Unbounded => (),
+    }
+
+    if conditions.is_empty() {
+        ("true".to_string(), binds)
+    } else {
+        (conditions.join(" and "), binds)
+    }
+}
+
+/// the value to bind for a sql `limit`, where -1 is no limit
+pub(crate) fn sql_limit(limit: Option<u32>) -> i64 {
+    limit.map_or(-1, i64::from)
+}
+
+/// the upper bound of the keys starting with `prefix`
+pub(crate) fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+
+    Bound::Unbounded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_ends() {
+        assert_eq!(prefix_end(b"abc"), Bound::Excluded(b"abd".to_vec()));
+        assert_eq!(prefix_end(&[0x01, 0xff]), Bound::Excluded(vec![0x02]));
+        assert_eq!(prefix_end(&[0xff, 0xff]), Bound::Unbounded);
+        assert_eq!(prefix_end(b""), Bound::Unbounded);
+    }
 }
@@ -1,119 +1,198 @@
-use super::{private, Storage};
-use crate::{begin_immediate::SqliteConnectionExt, Error, Options};
-use serde::{de::DeserializeOwned, Serialize};
-use std::str::FromStr;
+use super::{connect, private, range_condition, sql_limit, Storage, EVENTS_CAPACITY};
+use crate::{
+    begin_immediate::SqliteConnectionExt,
+    changes,
+    codec::EncodedValue,
+    index,
+    index::Indexes,
+    maintenance::{self, Command, Maintenance},
+    schema,
+    schema::Strategy,
+    sqlite_timestamp, Codec, Error, Event, EventKind, GarbageCollected, Options, Retention,
+    Revision,
+};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::ops::Bound;
+use tokio::sync::{broadcast, mpsc};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct UpdateInPlace {
     pub(crate) pool: sqlx::sqlite::SqlitePool,
+    pub(crate) codec: Codec,
+    pub(crate) events: broadcast::Sender<Event>,
+    pub(crate) bucket: String,
+    pub(crate) indexes: Indexes,
 }
 
 impl private::Sealed for UpdateInPlace {}
 
 impl Storage for UpdateInPlace {
+    const STRATEGY: Strategy = Strategy::UpdateInPlace;
+
     async fn open(options: Options) -> Result<Self, Error>
     where
         Self: Sized,
     {
-        let db_path = if options.in_memory {
-            "sqlite::memory:".to_string()
-        } else if let Some(p) = options.db_path {
-            p.to_str().unwrap().to_string()
+        let pool = connect(&options).await?;
+
+        let mut conn = pool.acquire().await?;
+
+        // a read-only handle can't create or migrate tables,
+        // so they must already exist
+        if options.read_only {
+            schema::check(&mut conn, Self::STRATEGY, options.codec).await?;
         } else {
-            "sqlite://kvqlite.db".to_string()
-        };
+            let mut tx = conn.begin_immediate().await?;
 
-        let pool_options = sqlx::sqlite::SqliteConnectOptions::from_str(&db_path)?
-            .busy_timeout(std::time::Duration::from_secs(5))
-            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
-            .create_if_missing(true);
+            schema::open(&mut tx, Self::STRATEGY, options.codec).await?;
 
-        let pool = sqlx::SqlitePool::connect_with(pool_options).await?;
+            create_tables(&mut tx).await?;
 
-        let mut conn = pool.acquire().await?;
+            index::open(&mut tx).await?;
 
-        let mut tx = conn.begin_immediate().await?;
+            changes::open(&mut tx, options.change_log).await?;
 
-        sqlx::query(
-            "create table if not exists kvs (
-            key blob not null primary key,
-            value blob not null,
-            inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
-            updated_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
-        )
-        ",
-        )
-        .execute(&mut *tx)
-        .await?;
+            tx.commit().await?;
+        }
+
+        Ok(Self {
+            pool,
+            codec: options.codec,
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            bucket: String::new(),
+            indexes: Indexes::default(),
+        })
+    }
+
+    fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn events(&self) -> &broadcast::Sender<Event> {
+        &self.events
+    }
+
+    fn with_bucket(&self, bucket: &str) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+            ..self.clone()
+        }
+    }
 
-        tx.commit().await?;
+    fn bucket(&self) -> &str {
+        &self.bucket
+    }
 
-        Ok(Self { pool })
+    fn indexes(&self) -> &Indexes {
+        &self.indexes
     }
 
-    async fn read<K, V>(&self, key: &K) -> Result<Option<V>, Error>
+    async fn read<K, V>(&self, conn: &mut SqliteConnection, key: &K) -> Result<Option<V>, Error>
     where
         K: AsRef<[u8]> + ?Sized,
         V: DeserializeOwned,
     {
-        let mut conn = self.pool.acquire().await?;
-
         let value_bytes: Option<(Vec<u8>,)> = sqlx::query_as(
             "
         select
             value
         from kvs
-        where key = ?;
+        where bucket = ?
+        and key = ?
+        and (expires_at is null or expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'));
         ",
         )
+        .bind(&self.bucket)
         .bind(key.as_ref())
         .fetch_optional(&mut *conn)
         .await?;
 
         if let Some((value_bytes,)) = value_bytes {
-            Ok(ciborium::from_reader(&value_bytes[..])?)
+            Ok(Some(self.codec.deserialize(&value_bytes)?))
         } else {
             Ok(None)
         }
     }
 
-    async fn write<K, V>(&self, key: &K, value: &V) -> Result<(), Error>
+    async fn write<K>(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &K,
+        value: &EncodedValue,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Revision, Error>
     where
         K: AsRef<[u8]> + ?Sized,
-        V: Serialize + ?Sized,
     {
-        let mut conn = self.pool.acquire().await?;
-
-        let mut value_bytes = vec![];
-        ciborium::into_writer(value, &mut value_bytes)?;
+        let version = schema::next_revision(conn).await?;
 
         sqlx::query(
             "
-            insert into kvs(key, value)
-            values(?, ?)
-            on conflict(key) do update set value = excluded.value;
+            insert into kvs(bucket, key, value, version, expires_at)
+            values(?, ?, ?, ?, ?)
+            on conflict(bucket, key) do update set
+                value = excluded.value,
+                version = excluded.version,
+                expires_at = excluded.expires_at,
+                updated_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
         ",
         )
+        .bind(&self.bucket)
         .bind(key.as_ref())
-        .bind(value_bytes)
+        .bind(value)
+        .bind(version)
+        .bind(expires_at.map(sqlite_timestamp))
         .execute(&mut *conn)
         .await?;
 
-        Ok(())
+        Ok(Revision(version))
     }
 
-    async fn delete<K>(&self, key: &K) -> Result<(), Error>
+    async fn current<K>(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &K,
+    ) -> Result<Option<(Vec<u8>, Revision)>, Error>
     where
         K: AsRef<[u8]> + ?Sized,
     {
-        let mut conn = self.pool.acquire().await?;
+        let current: Option<(Vec<u8>, i64)> = sqlx::query_as(
+            "
+        select
+            value,
+            version
+        from kvs
+        where bucket = ?
+        and key = ?
+        and (expires_at is null or expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'));
+        ",
+        )
+        .bind(&self.bucket)
+        .bind(key.as_ref())
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        Ok(current.map(|(value_bytes, version)| (value_bytes, Revision(version))))
+    }
 
+    async fn delete<K>(&self, conn: &mut SqliteConnection, key: &K) -> Result<(), Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
         sqlx::query(
             "
         delete from kvs
-        where key = ?
+        where bucket = ?
+        and key = ?
         ",
         )
+        .bind(&self.bucket)
         .bind(key.as_ref())
         .execute(&mut *conn)
         .await?;
@@ -121,15 +200,162 @@ impl Storage for UpdateInPlace {
         Ok(())
     }
 
-    /// the distinct number of keys in the system
-    async fn keys_count(&self) -> Result<u64, Error> {
-        let mut conn = self.pool.acquire().await?;
+    async fn range<V>(
+        &self,
+        conn: &mut SqliteConnection,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: Option<u32>,
+    ) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        V: DeserializeOwned,
+    {
+        let (condition, binds) = range_condition("key", start, end);
+
+        let sql = format!(
+            "
+            select
+                key,
+                value
+            from kvs
+            where bucket = ?
+            and {condition}
+            and (expires_at is null or expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+            order by key
+            limit ?
+            "
+        );
+
+        let mut query = sqlx::query_as(&sql).bind(&self.bucket);
+
+        for bind in binds {
+            query = query.bind(bind);
+        }
+
+        let rows: Vec<(Vec<u8>, Vec<u8>)> =
+            query.bind(sql_limit(limit)).fetch_all(&mut *conn).await?;
+
+        rows.into_iter()
+            .map(|(key, value_bytes)| Ok((key, self.codec.deserialize(&value_bytes)?)))
+            .collect()
+    }
+
+    async fn range_keys(
+        &self,
+        conn: &mut SqliteConnection,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: Option<u32>,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let (condition, binds) = range_condition("key", start, end);
+
+        let sql = format!(
+            "
+            select
+                key
+            from kvs
+            where bucket = ?
+            and {condition}
+            and (expires_at is null or expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+            order by key
+            limit ?
+            "
+        );
+
+        let mut query = sqlx::query_as(&sql).bind(&self.bucket);
+
+        for bind in binds {
+            query = query.bind(bind);
+        }
 
+        let keys: Vec<Vec<u8>> = query
+            .bind(sql_limit(limit))
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|(key,)| key)
+            .collect();
+
+        Ok(keys)
+    }
+
+    async fn clear(&self, conn: &mut SqliteConnection) -> Result<(), Error> {
+        sqlx::query(
+            "
+            delete from kvs
+            where bucket = ?
+            ",
+        )
+        .bind(&self.bucket)
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn purge_expired(&self, conn: &mut SqliteConnection) -> Result<u64, Error> {
+        // expired keys read as deleted, and the change log records
+        // the delete when they are purged
+        if changes::exists(conn).await? {
+            let expired: Vec<(Vec<u8>,)> = sqlx::query_as(
+                "
+                select
+                    key
+                from kvs
+                where bucket = ?
+                and expires_at <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+                ",
+            )
+            .bind(&self.bucket)
+            .fetch_all(&mut *conn)
+            .await?;
+
+            for (key,) in expired {
+                changes::record(conn, EventKind::Delete, &self.bucket, &key, None).await?;
+            }
+        }
+
+        sqlx::query(
+            "
+            delete from index_entries
+            where bucket = ?1
+            and key in (
+                select
+                    key
+                from kvs
+                where bucket = ?1
+                and expires_at <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+            )
+            ",
+        )
+        .bind(&self.bucket)
+        .execute(&mut *conn)
+        .await?;
+
+        let result = sqlx::query(
+            "
+            delete from kvs
+            where bucket = ?
+            and expires_at <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+            ",
+        )
+        .bind(&self.bucket)
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// the distinct number of keys in the system
+    async fn keys_count(&self, conn: &mut SqliteConnection) -> Result<u64, Error> {
         let (entries_count,): (u64,) = sqlx::query_as(
             "
             select count(*) from kvs
+            where bucket = ?
+            and (expires_at is null or expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
             ",
         )
+        .bind(&self.bucket)
         .fetch_one(&mut *conn)
         .await?;
 
@@ -137,16 +363,17 @@ impl Storage for UpdateInPlace {
     }
 
     /// all distinct keys in the system
-    async fn keys(&self) -> Result<Vec<Vec<u8>>, Error> {
-        let mut conn = self.pool.acquire().await?;
-
+    async fn keys(&self, conn: &mut SqliteConnection) -> Result<Vec<Vec<u8>>, Error> {
         let keys: Vec<Vec<u8>> = sqlx::query_as(
             "
             select
                 key
             from kvs
+            where bucket = ?
+            and (expires_at is null or expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
             ",
         )
+        .bind(&self.bucket)
         .fetch_all(&mut *conn)
         .await?
         .into_iter()
@@ -155,12 +382,70 @@ impl Storage for UpdateInPlace {
 
         Ok(keys)
     }
+
+    /// the names of the buckets that have keys
+    async fn buckets(&self, conn: &mut SqliteConnection) -> Result<Vec<String>, Error> {
+        let buckets: Vec<String> = sqlx::query_as(
+            "
+            select distinct
+                bucket
+            from kvs
+            order by bucket
+            ",
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|(bucket,)| bucket)
+        .collect();
+
+        Ok(buckets)
+    }
+
+    /// nothing is kept but the current values, so there is no garbage
+    async fn collect_garbage(&self, _retention: Retention) -> Result<GarbageCollected, Error> {
+        Ok(GarbageCollected::default())
+    }
+
+    fn start_maintenance(&self, maintenance: Maintenance) -> mpsc::UnboundedSender<Command> {
+        let (commands, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(maintenance::run(self.clone(), maintenance, receiver));
+
+        commands
+    }
+}
+
+pub(crate) async fn create_tables(conn: &mut SqliteConnection) -> Result<(), Error> {
+    sqlx::query(
+        "create table if not exists kvs (
+        bucket text not null default '',
+        key blob not null,
+        value blob not null,
+        version integer not null default 1,
+        expires_at datetime,
+        inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+        updated_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+
+        primary key (bucket, key)
+    )
+    ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Db;
+    use crate::storage::append::Append;
+    use crate::{
+        AsOf, Codec, Db, Element, Error, EventKind, Expiry, JournalMode, Maintenance, ReadOnlyDb,
+        Synchronous, Tuple, TypedDb,
+    };
+    use futures::{StreamExt, TryStreamExt};
 
     #[tokio::test]
     async fn roundtrip() {
@@ -214,4 +499,1626 @@ mod tests {
         let value = db.read::<str, String>("hello").await.unwrap();
         assert!(value.is_none())
     }
+
+    #[tokio::test]
+    async fn range() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        for key in ["d", "a", "c", "b", "e"] {
+            db.write(key, &key.to_uppercase()).await.unwrap();
+        }
+
+        let entries: Vec<(Vec<u8>, String)> = db.range("b".."d").await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"b".to_vec(), "B".to_string()),
+                (b"c".to_vec(), "C".to_string())
+            ]
+        );
+
+        let entries: Vec<(Vec<u8>, String)> = db.range("b"..="d").await.unwrap();
+        let keys: Vec<Vec<u8>> = entries.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+
+        let entries: Vec<(Vec<u8>, String)> = db.range("c"..).await.unwrap();
+        let keys: Vec<Vec<u8>> = entries.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![b"c".to_vec(), b"d".to_vec(), b"e".to_vec()]);
+
+        let entries: Vec<(Vec<u8>, String)> = db.range::<&str, _, _>(..).await.unwrap();
+        assert_eq!(entries.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn prefix() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("tenant/a/2", "a2").await.unwrap();
+        db.write("tenant/b/1", "b1").await.unwrap();
+        db.write("tenant/a/1", "a1").await.unwrap();
+        db.write("tenant/ab", "ab").await.unwrap();
+        db.write("other", "other").await.unwrap();
+        db.write(&[0xff_u8, 0xff], "high").await.unwrap();
+
+        let entries: Vec<(Vec<u8>, String)> = db.prefix("tenant/a/").await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"tenant/a/1".to_vec(), "a1".to_string()),
+                (b"tenant/a/2".to_vec(), "a2".to_string())
+            ]
+        );
+
+        let entries: Vec<(Vec<u8>, String)> = db.prefix(&[0xff_u8]).await.unwrap();
+        assert_eq!(entries, vec![(vec![0xff, 0xff], "high".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn streams() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        // more than one page
+        for i in (0..2500u32).rev() {
+            db.write(&i.to_be_bytes(), &i).await.unwrap();
+        }
+
+        let keys: Vec<Vec<u8>> = db.stream_keys().try_collect().await.unwrap();
+        let expected: Vec<Vec<u8>> = (0..2500u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(keys, expected);
+
+        let entries: Vec<(Vec<u8>, u32)> = db.stream_entries().try_collect().await.unwrap();
+        assert_eq!(entries.len(), 2500);
+        assert!(entries
+            .iter()
+            .all(|(key, value)| key[..] == value.to_be_bytes()));
+        assert!(entries.windows(2).all(|w| w[0].1 + 1 == w[1].1));
+    }
+
+    #[tokio::test]
+    async fn transactions() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("alice", &100).await.unwrap();
+        db.write("bob", &0).await.unwrap();
+
+        let mut tx = db.transaction().await.unwrap();
+        let alice: i32 = tx.read("alice").await.unwrap().unwrap();
+        let bob: i32 = tx.read("bob").await.unwrap().unwrap();
+        tx.write("alice", &(alice - 30)).await.unwrap();
+        tx.write("bob", &(bob + 30)).await.unwrap();
+
+        let balances: Vec<(Vec<u8>, i32)> = tx.range("alice"..="bob").await.unwrap();
+        assert_eq!(
+            balances,
+            vec![(b"alice".to_vec(), 70), (b"bob".to_vec(), 30)]
+        );
+
+        tx.commit().await.unwrap();
+
+        let alice: i32 = db.read("alice").await.unwrap().unwrap();
+        let bob: i32 = db.read("bob").await.unwrap().unwrap();
+        assert_eq!((alice, bob), (70, 30));
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.write("alice", &0).await.unwrap();
+        tx.delete("bob").await.unwrap();
+        assert!(tx.read::<str, i32>("bob").await.unwrap().is_none());
+        tx.rollback().await.unwrap();
+
+        let alice: i32 = db.read("alice").await.unwrap().unwrap();
+        let bob: i32 = db.read("bob").await.unwrap().unwrap();
+        assert_eq!((alice, bob), (70, 30));
+
+        {
+            let mut tx = db.transaction().await.unwrap();
+            tx.write("carol", &1).await.unwrap();
+            let entries: Vec<(Vec<u8>, i32)> = tx.prefix("c").await.unwrap();
+            assert_eq!(entries, vec![(b"carol".to_vec(), 1)]);
+        }
+
+        assert!(db.read::<str, i32>("carol").await.unwrap().is_none());
+        assert_eq!(db.keys_count().await.unwrap(), 2);
+
+        // the dropped transaction released the write lock
+        db.write("carol", &2).await.unwrap();
+        let carol: i32 = db.read("carol").await.unwrap().unwrap();
+        assert_eq!(carol, 2);
+    }
+
+    #[tokio::test]
+    async fn conditional_writes() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.insert_if_absent("hello", "world").await.unwrap();
+        assert!(matches!(
+            db.insert_if_absent("hello", "joe").await,
+            Err(Error::ConditionFailed)
+        ));
+        assert!(matches!(
+            db.update_if_present("nope", "joe").await,
+            Err(Error::ConditionFailed)
+        ));
+        assert!(db.read::<str, String>("nope").await.unwrap().is_none());
+
+        db.compare_and_swap("hello", Some("world"), "joe")
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.compare_and_swap("hello", Some("world"), "mike").await,
+            Err(Error::ConditionFailed)
+        ));
+        assert!(matches!(
+            db.compare_and_swap("hello", None, "mike").await,
+            Err(Error::ConditionFailed)
+        ));
+        db.compare_and_swap("other", None, "value").await.unwrap();
+
+        let (value, revision): (String, _) = db.read_with_revision("hello").await.unwrap().unwrap();
+        assert_eq!(value, "joe");
+
+        let new_revision = db
+            .write_if_revision("hello", revision, "mike")
+            .await
+            .unwrap();
+        assert_ne!(new_revision, revision);
+        assert!(matches!(
+            db.write_if_revision("hello", revision, "robert").await,
+            Err(Error::ConditionFailed)
+        ));
+
+        db.update_if_present("hello", "robert").await.unwrap();
+
+        let value: String = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, "robert");
+    }
+
+    #[tokio::test]
+    async fn revisions_are_not_reused() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("hello", "one").await.unwrap();
+        let (_, revision): (String, _) = db.read_with_revision("hello").await.unwrap().unwrap();
+
+        db.delete("hello").await.unwrap();
+        db.write("hello", "two").await.unwrap();
+
+        assert!(matches!(
+            db.write_if_revision("hello", revision, "stale").await,
+            Err(Error::ConditionFailed)
+        ));
+        let value: String = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, "two");
+
+        // converting doesn't hand out revisions again either
+        let (_, revision): (String, _) = db.read_with_revision("hello").await.unwrap().unwrap();
+        let db = db.into_append().await.unwrap();
+        db.delete("hello").await.unwrap();
+        db.write("hello", "three").await.unwrap();
+        assert!(matches!(
+            db.write_if_revision("hello", revision, "stale").await,
+            Err(Error::ConditionFailed)
+        ));
+
+        let (_, revision): (String, _) = db.read_with_revision("hello").await.unwrap().unwrap();
+        let db = db.into_update_in_place().await.unwrap();
+        let (_, converted): (String, _) = db.read_with_revision("hello").await.unwrap().unwrap();
+        assert_eq!(converted, revision);
+        db.delete("hello").await.unwrap();
+        db.write("hello", "four").await.unwrap();
+        assert!(matches!(
+            db.write_if_revision("hello", revision, "stale").await,
+            Err(Error::ConditionFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("keep", "forever").await.unwrap();
+        db.write_with_expiry(
+            "session",
+            "abc",
+            Expiry::After(std::time::Duration::from_millis(50)),
+        )
+        .await
+        .unwrap();
+        db.write_with_expiry(
+            "renewed",
+            "abc",
+            Expiry::After(std::time::Duration::from_millis(50)),
+        )
+        .await
+        .unwrap();
+        db.write_with_expiry("expired", "abc", Expiry::At(chrono::Utc::now()))
+            .await
+            .unwrap();
+
+        assert!(db.read::<str, String>("expired").await.unwrap().is_none());
+        assert!(db.read::<str, String>("session").await.unwrap().is_some());
+        assert_eq!(db.keys_count().await.unwrap(), 3);
+
+        db.write("renewed", "def").await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+
+        assert!(db.read::<str, String>("session").await.unwrap().is_none());
+        assert_eq!(db.keys_count().await.unwrap(), 2);
+        assert_eq!(db.keys().await.unwrap().len(), 2);
+
+        let entries: Vec<(Vec<u8>, String)> = db.prefix("").await.unwrap();
+        let keys: Vec<Vec<u8>> = entries.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![b"keep".to_vec(), b"renewed".to_vec()]);
+
+        let keys: Vec<Vec<u8>> = db.stream_keys().try_collect().await.unwrap();
+        assert_eq!(keys, vec![b"keep".to_vec(), b"renewed".to_vec()]);
+
+        assert_eq!(db.purge_expired().await.unwrap(), 2);
+        assert_eq!(db.purge_expired().await.unwrap(), 0);
+
+        db.insert_if_absent("session", "new").await.unwrap();
+        let value: String = db.read("session").await.unwrap().unwrap();
+        assert_eq!(value, "new");
+    }
+
+    #[tokio::test]
+    async fn maintenance_purges_expired() {
+        let db: Db<UpdateInPlace> = Db::builder()
+            .in_memory()
+            .maintenance(Maintenance::every(std::time::Duration::from_secs(60 * 60)))
+            .finish()
+            .await
+            .unwrap();
+        let other = db.bucket("other");
+
+        db.write_with_expiry("a", "abc", Expiry::At(chrono::Utc::now()))
+            .await
+            .unwrap();
+        other
+            .write_with_expiry("b", "abc", Expiry::At(chrono::Utc::now()))
+            .await
+            .unwrap();
+        db.write("keep", "abc").await.unwrap();
+
+        let report = db.maintenance().unwrap().trigger().await.unwrap();
+        assert_eq!(report.expired, 2);
+        assert_eq!(report.garbage, Default::default());
+        assert!(!report.vacuumed);
+        assert_eq!(db.purge_expired().await.unwrap(), 0);
+        assert_eq!(db.keys().await.unwrap(), vec![b"keep".to_vec()]);
+
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+        assert!(db.maintenance().is_none());
+    }
+
+    #[tokio::test]
+    async fn maintenance_after_conversion() {
+        let db: Db<UpdateInPlace> = Db::builder()
+            .in_memory()
+            .maintenance(Maintenance::every(std::time::Duration::from_secs(60 * 60)))
+            .finish()
+            .await
+            .unwrap();
+        let old = db.maintenance().unwrap();
+
+        let db = db.into_append().await.unwrap();
+        assert!(matches!(
+            old.trigger().await,
+            Err(Error::MaintenanceStopped)
+        ));
+
+        db.write_with_expiry("a", "abc", Expiry::At(chrono::Utc::now()))
+            .await
+            .unwrap();
+        let report = db.maintenance().unwrap().trigger().await.unwrap();
+        assert_eq!(report.expired, 1);
+
+        let db = db.into_update_in_place().await.unwrap();
+        db.write_with_expiry("b", "abc", Expiry::At(chrono::Utc::now()))
+            .await
+            .unwrap();
+        let report = db.maintenance().unwrap().trigger().await.unwrap();
+        assert_eq!(report.expired, 1);
+        assert!(db.keys().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write_many((0..1000u32).map(|i| (i.to_be_bytes(), i)))
+            .await
+            .unwrap();
+
+        assert_eq!(db.keys_count().await.unwrap(), 1000);
+
+        let values: Vec<Option<u32>> = db
+            .read_many([7u32, 1000, 3].map(u32::to_be_bytes))
+            .await
+            .unwrap();
+        assert_eq!(values, vec![Some(7), None, Some(3)]);
+
+        db.delete_many((0..500u32).map(u32::to_be_bytes))
+            .await
+            .unwrap();
+
+        assert_eq!(db.keys_count().await.unwrap(), 500);
+
+        db.write_many([("a", "b"), ("c", "d")]).await.unwrap();
+        let values: Vec<Option<String>> = db.read_many(["c", "a"]).await.unwrap();
+        assert_eq!(values, vec![Some("d".to_string()), Some("b".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn codecs() {
+        for codec in [
+            Codec::Cbor,
+            Codec::Json,
+            Codec::MessagePack,
+            Codec::Postcard,
+        ] {
+            let db: Db<UpdateInPlace> = Db::builder()
+                .in_memory()
+                .codec(codec)
+                .finish()
+                .await
+                .unwrap();
+
+            db.write("hello", &("world", 1)).await.unwrap();
+            let value: (String, i32) = db.read("hello").await.unwrap().unwrap();
+            assert_eq!(value, ("world".to_string(), 1));
+
+            let entries: Vec<(Vec<u8>, (String, i32))> = db.prefix("h").await.unwrap();
+            assert_eq!(entries.len(), 1);
+        }
+
+        let db: Db<UpdateInPlace> = Db::builder()
+            .in_memory()
+            .codec(Codec::Raw)
+            .finish()
+            .await
+            .unwrap();
+
+        db.write("hello", &b"world"[..]).await.unwrap();
+        let value: Vec<u8> = db.read("hello").await.unwrap().unwrap();
+        assert_eq!(value, b"world");
+    }
+
+    #[tokio::test]
+    async fn path_index_codecs() {
+        for codec in [Codec::Cbor, Codec::Json, Codec::MessagePack] {
+            let db: Db<UpdateInPlace> = Db::builder()
+                .in_memory()
+                .codec(codec)
+                .finish()
+                .await
+                .unwrap();
+
+            db.write("int", &serde_json::json!({"age": 31}))
+                .await
+                .unwrap();
+            db.write("float", &serde_json::json!({"age": 31.0}))
+                .await
+                .unwrap();
+            db.write("half", &serde_json::json!({"age": 31.5}))
+                .await
+                .unwrap();
+            db.create_path_index("age", "age").await.unwrap();
+
+            let found: Vec<(Vec<u8>, serde_json::Value)> =
+                db.index_get("age", &Element::Int(31)).await.unwrap();
+            let keys: Vec<&[u8]> = found.iter().map(|(key, _)| &key[..]).collect();
+            assert_eq!(keys, vec![&b"float"[..], b"int"], "{codec:?}");
+
+            let found: Vec<(Vec<u8>, serde_json::Value)> =
+                db.index_get("age", &Element::Double(31.5)).await.unwrap();
+            assert_eq!(found.len(), 1, "{codec:?}");
+        }
+
+        for codec in [Codec::Postcard, Codec::Raw] {
+            let db: Db<UpdateInPlace> = Db::builder()
+                .in_memory()
+                .codec(codec)
+                .finish()
+                .await
+                .unwrap();
+
+            assert!(matches!(
+                db.create_path_index("age", "age").await,
+                Err(Error::UnsupportedCodec(found)) if found == codec
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        let mut events = std::pin::pin!(db.watch("a"));
+
+        db.write("a", "b").await.unwrap();
+        db.write("other", "x").await.unwrap();
+        db.write("a", "c").await.unwrap();
+        db.delete("a").await.unwrap();
+        // deleting a missing key is not a change
+        db.delete("a").await.unwrap();
+        db.write("a", "d").await.unwrap();
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Put);
+        assert_eq!(event.key(), b"a");
+        assert_eq!(event.old_value::<String>().unwrap(), None);
+        assert_eq!(event.new_value::<String>().unwrap(), Some("b".to_string()));
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Put);
+        assert_eq!(event.old_value::<String>().unwrap(), Some("b".to_string()));
+        assert_eq!(event.new_value::<String>().unwrap(), Some("c".to_string()));
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Delete);
+        assert_eq!(event.old_value::<String>().unwrap(), Some("c".to_string()));
+        assert_eq!(event.new_value::<String>().unwrap(), None);
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Put);
+        assert_eq!(event.old_value::<String>().unwrap(), None);
+        assert_eq!(event.new_value::<String>().unwrap(), Some("d".to_string()));
+    }
+
+    #[tokio::test]
+    async fn watch_during_transaction() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("a", "1").await.unwrap();
+        db.write("b", "2").await.unwrap();
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.write("a", "3").await.unwrap();
+        tx.delete("b").await.unwrap();
+
+        // subscribed after the writes but before the commit
+        let mut events = std::pin::pin!(db.watch_prefix(""));
+        tx.commit().await.unwrap();
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Put);
+        assert_eq!(event.old_value::<String>().unwrap(), Some("1".to_string()));
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Delete);
+        assert_eq!(event.old_value::<String>().unwrap(), Some("2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn watch_prefix() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        let mut events = std::pin::pin!(db.watch_prefix("config/"));
+
+        db.write("config/a", "1").await.unwrap();
+        db.write("other", "x").await.unwrap();
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.write("config/b", "rolled back").await.unwrap();
+        tx.rollback().await.unwrap();
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.write("config/c", "3").await.unwrap();
+        tx.delete("config/a").await.unwrap();
+        tx.commit().await.unwrap();
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.kind(), event.key()),
+            (EventKind::Put, &b"config/a"[..])
+        );
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.kind(), event.key()),
+            (EventKind::Put, &b"config/c"[..])
+        );
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.kind(), event.key()),
+            (EventKind::Delete, &b"config/a"[..])
+        );
+        assert_eq!(event.old_value::<String>().unwrap(), Some("1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn change_log() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+        assert!(matches!(db.changes(0, 10).await, Err(Error::NoChangeLog)));
+
+        let db: Db<UpdateInPlace> = Db::builder()
+            .in_memory()
+            .change_log()
+            .finish()
+            .await
+            .unwrap();
+
+        db.write("a", "b").await.unwrap();
+        db.delete("a").await.unwrap();
+        // deleting a missing key is not a change
+        db.delete("a").await.unwrap();
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.write("c", "rolled back").await.unwrap();
+        tx.rollback().await.unwrap();
+
+        db.write_many([("c", "d"), ("e", "f")]).await.unwrap();
+
+        let changes = db.changes(0, 10).await.unwrap();
+        let summary: Vec<(u64, EventKind, &[u8], Option<String>)> = changes
+            .iter()
+            .map(|change| {
+                (
+                    change.seq(),
+                    change.kind(),
+                    change.key(),
+                    change.value().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, EventKind::Put, &b"a"[..], Some("b".to_string())),
+                (2, EventKind::Delete, &b"a"[..], None),
+                (3, EventKind::Put, &b"c"[..], Some("d".to_string())),
+                (4, EventKind::Put, &b"e"[..], Some("f".to_string())),
+            ]
+        );
+
+        let changes = db.changes(2, 1).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].seq(), 3);
+
+        assert_eq!(db.truncate_changes(3).await.unwrap(), 3);
+        db.write("g", "h").await.unwrap();
+        let seqs: Vec<u64> = db
+            .changes(0, 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|change| change.seq())
+            .collect();
+        assert_eq!(seqs, vec![4, 5]);
+    }
+
+    #[tokio::test]
+    async fn change_log_created_by_another_handle() {
+        let path = std::env::temp_dir().join(format!(
+            "kvqlite-change-log-created-{}.db",
+            std::process::id()
+        ));
+
+        // opened before the database has a change log
+        let a: Db<UpdateInPlace> = Db::builder().with_db_path(&path).finish().await.unwrap();
+        let b: Db<UpdateInPlace> = Db::builder()
+            .with_db_path(&path)
+            .change_log()
+            .finish()
+            .await
+            .unwrap();
+
+        a.write("a", "1").await.unwrap();
+        a.write_with_expiry(
+            "b",
+            "2",
+            Expiry::At(chrono::Utc::now() - chrono::Duration::seconds(1)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(a.purge_expired().await.unwrap(), 1);
+
+        let changes = a.changes(0, 10).await.unwrap();
+        let summary: Vec<(EventKind, &[u8])> = changes
+            .iter()
+            .map(|change| (change.kind(), change.key()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (EventKind::Put, &b"a"[..]),
+                (EventKind::Put, &b"b"[..]),
+                (EventKind::Delete, &b"b"[..]),
+            ]
+        );
+
+        a.storage.pool.close().await;
+        b.storage.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn tail_changes_from_another_handle() {
+        let path =
+            std::env::temp_dir().join(format!("kvqlite-tail-changes-{}.db", std::process::id()));
+
+        let writer: Db<UpdateInPlace> = Db::builder()
+            .with_db_path(&path)
+            .change_log()
+            .finish()
+            .await
+            .unwrap();
+
+        // opened without `change_log`, but the database already has one
+        let reader: Db<UpdateInPlace> = Db::builder().with_db_path(&path).finish().await.unwrap();
+
+        reader.write("a", "1").await.unwrap();
+        writer.write("b", "2").await.unwrap();
+        writer.delete("a").await.unwrap();
+
+        let changes: Vec<_> = reader
+            .tail_changes(1, std::time::Duration::from_millis(10))
+            .take(2)
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert_eq!(changes[0].seq(), 2);
+        assert_eq!(changes[0].key(), b"b");
+        assert_eq!(changes[1].seq(), 3);
+        assert_eq!(changes[1].kind(), EventKind::Delete);
+
+        writer.storage.pool.close().await;
+        reader.storage.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        let path = std::env::temp_dir().join(format!("kvqlite-restore-{}.db", std::process::id()));
+        let backup = std::env::temp_dir().join(format!("kvqlite-backup-{}.db", std::process::id()));
+
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+        db.write("a", "1").await.unwrap();
+        db.bucket("other").write("b", "2").await.unwrap();
+
+        let mut reports = vec![];
+        db.backup_to_with_progress(&backup, |progress| reports.push(progress))
+            .await
+            .unwrap();
+        let last = reports.last().unwrap();
+        assert_eq!(last.copied, last.total);
+        assert!(last.total > 0);
+
+        // the backup must be a new file
+        assert!(db.backup_to(&backup).await.is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            let not_utf8 =
+                std::env::temp_dir().join(std::ffi::OsStr::from_bytes(b"kvqlite-\xff.db"));
+            assert!(matches!(
+                db.backup_to(&not_utf8).await,
+                Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+            ));
+        }
+
+        let live: Db<UpdateInPlace> = Db::builder().with_db_path(&path).finish().await.unwrap();
+        live.write("a", "old").await.unwrap();
+        live.storage.pool.close().await;
+
+        Db::<UpdateInPlace>::restore(&backup, &path).await.unwrap();
+
+        let restored: Db<UpdateInPlace> = Db::builder().with_db_path(&path).finish().await.unwrap();
+        let value: String = restored.read("a").await.unwrap().unwrap();
+        assert_eq!(value, "1");
+        let value: String = restored.bucket("other").read("b").await.unwrap().unwrap();
+        assert_eq!(value, "2");
+
+        assert!(matches!(
+            Db::<Append>::restore(&backup, &path).await,
+            Err(Error::StrategyMismatch(Strategy::UpdateInPlace))
+        ));
+
+        // an empty file is an empty database, without kvqlite's tables
+        std::fs::write(&backup, b"").unwrap();
+        assert!(matches!(
+            Db::<UpdateInPlace>::restore(&backup, &path).await,
+            Err(Error::InvalidBackup(_))
+        ));
+        let value: String = restored.read("a").await.unwrap().unwrap();
+        assert_eq!(value, "1");
+
+        restored.storage.pool.close().await;
+        for file in [&path, &backup] {
+            for suffix in ["", "-wal", "-shm", ".restoring"] {
+                let mut file = file.clone().into_os_string();
+                file.push(suffix);
+                let _ = std::fs::remove_file(file);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn buckets() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+        let users = db.bucket("users");
+        let teams = db.bucket("teams");
+
+        db.write("a", "default").await.unwrap();
+        users.write("a", "user a").await.unwrap();
+        users.write("b", "user b").await.unwrap();
+        teams.write("a", "team a").await.unwrap();
+
+        let value: String = db.read("a").await.unwrap().unwrap();
+        assert_eq!(value, "default");
+        let value: String = users.read("a").await.unwrap().unwrap();
+        assert_eq!(value, "user a");
+        let value: String = db.bucket("teams").read("a").await.unwrap().unwrap();
+        assert_eq!(value, "team a");
+
+        assert_eq!(db.keys_count().await.unwrap(), 1);
+        assert_eq!(users.keys_count().await.unwrap(), 2);
+        assert_eq!(
+            users.keys().await.unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+
+        let entries: Vec<(Vec<u8>, String)> = users.prefix("").await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), "user a".to_string()),
+                (b"b".to_vec(), "user b".to_string())
+            ]
+        );
+
+        teams.delete("a").await.unwrap();
+        let value: Option<String> = users.read("a").await.unwrap();
+        assert!(value.is_some());
+
+        db.drop_bucket("users").await.unwrap();
+        assert_eq!(users.keys_count().await.unwrap(), 0);
+        let value: Option<String> = db.read("a").await.unwrap();
+        assert!(value.is_some());
+    }
+
+    #[tokio::test]
+    async fn watch_bucket() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+        let users = db.bucket("users");
+
+        let mut events = std::pin::pin!(users.watch_prefix(""));
+
+        db.write("a", "default").await.unwrap();
+        users.write("a", "user a").await.unwrap();
+        db.drop_bucket("users").await.unwrap();
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!((event.bucket(), event.kind()), ("users", EventKind::Put));
+
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!((event.bucket(), event.kind()), ("users", EventKind::Delete));
+        assert_eq!(
+            event.old_value::<String>().unwrap(),
+            Some("user a".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn typed() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        let scores: TypedDb<i64, String, UpdateInPlace> = db.bucket("scores").typed();
+        for score in [256, -3, 10, 2] {
+            scores
+                .write(&score, &format!("score {score}"))
+                .await
+                .unwrap();
+        }
+        assert_eq!(scores.keys().await.unwrap(), vec![-3, 2, 10, 256]);
+        assert_eq!(
+            scores.range(0..100).await.unwrap(),
+            vec![(2, "score 2".to_string()), (10, "score 10".to_string())]
+        );
+
+        let teams: TypedDb<(String, u32), bool, UpdateInPlace> = db.bucket("teams").typed();
+        teams.write(&("b".to_string(), 1), &true).await.unwrap();
+        teams.write(&("a".to_string(), 10), &false).await.unwrap();
+        teams.write(&("a".to_string(), 9), &true).await.unwrap();
+        assert_eq!(teams.read(&("a".to_string(), 9)).await.unwrap(), Some(true));
+
+        let entries: Vec<((String, u32), bool)> =
+            teams.stream_entries().try_collect().await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (("a".to_string(), 9), true),
+                (("a".to_string(), 10), false),
+                (("b".to_string(), 1), true)
+            ]
+        );
+
+        teams.delete(&("b".to_string(), 1)).await.unwrap();
+        assert_eq!(teams.keys_count().await.unwrap(), 2);
+
+        // integers starting with 0xff after a string
+        let levels: TypedDb<(String, u8), i32, UpdateInPlace> = db.bucket("levels").typed();
+        levels.write(&("a".to_string(), 255), &1).await.unwrap();
+        levels.write(&("a".to_string(), 0), &2).await.unwrap();
+        assert_eq!(
+            levels.keys().await.unwrap(),
+            vec![("a".to_string(), 0), ("a".to_string(), 255)]
+        );
+        assert_eq!(
+            levels
+                .range(("a".to_string(), 1)..=("a".to_string(), 255))
+                .await
+                .unwrap(),
+            vec![(("a".to_string(), 255), 1)]
+        );
+
+        // string keys are the same typed and untyped
+        let names: TypedDb<String, u32, UpdateInPlace> = db.typed();
+        names.write(&"joe".to_string(), &1).await.unwrap();
+        let value: u32 = db.read("joe").await.unwrap().unwrap();
+        assert_eq!(value, 1);
+    }
+
+    #[tokio::test]
+    async fn tuple_keys() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        let user = |id: i64, field: &str| Tuple::new().push("user").push(id).push(field);
+        db.write(&user(10, "name").pack(), "joe").await.unwrap();
+        db.write(&user(-5, "name").pack(), "mike").await.unwrap();
+        db.write(&user(10, "age").pack(), "40").await.unwrap();
+        db.write(&Tuple::new().push("team").push(1).pack(), "a")
+            .await
+            .unwrap();
+
+        let users: Vec<Tuple> = db
+            .prefix::<_, String>(&Tuple::new().push("user").pack())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| Tuple::unpack(&key).unwrap())
+            .collect();
+        assert_eq!(
+            users,
+            vec![user(-5, "name"), user(10, "age"), user(10, "name")]
+        );
+
+        let typed: TypedDb<Tuple, String, UpdateInPlace> = db.typed();
+        let fields = typed.range(user(10, "")..user(11, "")).await.unwrap();
+        assert_eq!(
+            fields,
+            vec![
+                (user(10, "age"), "40".to_string()),
+                (user(10, "name"), "joe".to_string())
+            ]
+        );
+
+        // typed keys are the tuples of the same elements
+        let teams: TypedDb<(String, i64), String, UpdateInPlace> = db.typed();
+        teams
+            .write(&("team".to_string(), -1), &"b".to_string())
+            .await
+            .unwrap();
+        let value: Option<String> = db.read(&Tuple::from(("team", -1)).pack()).await.unwrap();
+        assert_eq!(value, Some("b".to_string()));
+        assert_eq!(
+            teams
+                .range(("team".to_string(), i64::MIN)..=("team".to_string(), i64::MAX))
+                .await
+                .unwrap(),
+            vec![
+                (("team".to_string(), -1), "b".to_string()),
+                (("team".to_string(), 1), "a".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_index_registration() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("a", &serde_json::json!({"id": "x", "name": "joe"}))
+            .await
+            .unwrap();
+        db.create_index("id", |value: &serde_json::Value| {
+            value["id"].as_str().map(str::to_string)
+        })
+        .await
+        .unwrap();
+
+        // building the replacement fails
+        sqlx::query(
+            "
+            create trigger fail_index before insert on index_entries
+            begin
+                select raise(abort, 'index entries are read only');
+            end
+            ",
+        )
+        .execute(&db.storage.pool)
+        .await
+        .unwrap();
+
+        assert!(db
+            .create_index("id", |value: &serde_json::Value| {
+                value["name"].as_str().map(str::to_string)
+            })
+            .await
+            .is_err());
+        assert!(db.create_path_index("id", "name").await.is_err());
+
+        sqlx::query("drop trigger fail_index")
+            .execute(&db.storage.pool)
+            .await
+            .unwrap();
+
+        // the index is still by id
+        db.write("b", &serde_json::json!({"id": "y", "name": "mike"}))
+            .await
+            .unwrap();
+        let found: Vec<(Vec<u8>, serde_json::Value)> =
+            db.index_get("id", &"y".to_string()).await.unwrap();
+        assert_eq!(found.len(), 1);
+        let found: Vec<(Vec<u8>, serde_json::Value)> =
+            db.index_get("id", &"x".to_string()).await.unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn indexes_across_handles() {
+        let path = std::env::temp_dir().join(format!(
+            "kvqlite-indexes-across-handles-{}.db",
+            std::process::id()
+        ));
+
+        let a: Db<UpdateInPlace> = Db::builder().with_db_path(&path).finish().await.unwrap();
+        let b: Db<UpdateInPlace> = Db::builder().with_db_path(&path).finish().await.unwrap();
+
+        a.write("k", &serde_json::json!({"id": "x"})).await.unwrap();
+
+        // b hasn't registered the closure, so doesn't update its entries
+        a.create_index("id", |value: &serde_json::Value| {
+            value["id"].as_str().map(str::to_string)
+        })
+        .await
+        .unwrap();
+        b.write("k", &serde_json::json!({"id": "y"})).await.unwrap();
+
+        let found: Vec<(Vec<u8>, serde_json::Value)> =
+            a.index_get("id", &"x".to_string()).await.unwrap();
+        assert!(found.is_empty());
+
+        // path indexes are stored, so b keeps them up to date and can read them
+        a.create_path_index("path_id", "id").await.unwrap();
+        b.write("k", &serde_json::json!({"id": "z"})).await.unwrap();
+
+        let found: Vec<(Vec<u8>, serde_json::Value)> =
+            a.index_get("path_id", &Element::from("z")).await.unwrap();
+        assert_eq!(found, vec![(b"k".to_vec(), serde_json::json!({"id": "z"}))]);
+        let found: Vec<(Vec<u8>, serde_json::Value)> =
+            b.index_get("path_id", &Element::from("z")).await.unwrap();
+        assert_eq!(found.len(), 1);
+
+        b.drop_index("path_id").await.unwrap();
+        assert!(matches!(
+            a.index_get::<_, serde_json::Value>("path_id", &Element::from("z"))
+                .await,
+            Err(Error::UnknownIndex(_))
+        ));
+
+        a.storage.pool.close().await;
+        b.storage.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn indexes() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        let user = |email: &str, age: i64, city: &str| serde_json::json!({"email": email, "age": age, "address": {"city": city}});
+
+        db.write("joe", &user("joe@example", 40, "paris"))
+            .await
+            .unwrap();
+        db.write("mike", &user("mike@example", 30, "rome"))
+            .await
+            .unwrap();
+
+        // indexes are built from existing values when registered
+        db.create_index("email", |user: &serde_json::Value| {
+            user["email"].as_str().map(str::to_string)
+        })
+        .await
+        .unwrap();
+        db.create_path_index("age", "age").await.unwrap();
+        db.create_path_index("city", "address.city").await.unwrap();
+
+        db.write("ann", &user("ann@example", 35, "paris"))
+            .await
+            .unwrap();
+        // not a user, so not indexed by email
+        db.write("other", "value").await.unwrap();
+
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"mike@example".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            found,
+            vec![(b"mike".to_vec(), user("mike@example", 30, "rome"))]
+        );
+
+        let found: Vec<(Vec<u8>, serde_json::Value)> =
+            db.index_range("age", Element::Int(31)..).await.unwrap();
+        let keys: Vec<&[u8]> = found.iter().map(|(key, _)| &key[..]).collect();
+        assert_eq!(keys, vec![&b"ann"[..], b"joe"]);
+
+        let found: Vec<(Vec<u8>, serde_json::Value)> =
+            db.index_get("city", &Element::from("paris")).await.unwrap();
+        let keys: Vec<&[u8]> = found.iter().map(|(key, _)| &key[..]).collect();
+        assert_eq!(keys, vec![&b"ann"[..], b"joe"]);
+
+        db.write("joe", &user("joe@new.example", 41, "paris"))
+            .await
+            .unwrap();
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"joe@example".to_string())
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"joe@new.example".to_string())
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+
+        let mut tx = db.transaction().await.unwrap();
+        tx.delete("mike").await.unwrap();
+        tx.rollback().await.unwrap();
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"mike@example".to_string())
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+
+        db.delete("mike").await.unwrap();
+        let found: Vec<(Vec<u8>, serde_json::Value)> = db
+            .index_get("email", &"mike@example".to_string())
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+
+        db.drop_index("email").await.unwrap();
+        let found: Result<Vec<(Vec<u8>, serde_json::Value)>, Error> =
+            db.index_get("email", &"ann@example".to_string()).await;
+        assert!(matches!(found, Err(Error::UnknownIndex(_))));
+
+        // indexes belong to a bucket
+        let found: Result<Vec<(Vec<u8>, serde_json::Value)>, Error> =
+            db.bucket("other").index_get("age", &Element::Int(35)).await;
+        assert!(matches!(found, Err(Error::UnknownIndex(_))));
+    }
+
+    #[tokio::test]
+    async fn in_memory_is_shared_by_connections() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("a", "b").await.unwrap();
+
+        let mut conns = vec![];
+        for _ in 0..3 {
+            conns.push(db.storage.pool.acquire().await.unwrap());
+        }
+
+        for conn in &mut conns {
+            let (count,): (i64,) = sqlx::query_as("select count(*) from kvs")
+                .fetch_one(&mut **conn)
+                .await
+                .unwrap();
+            assert_eq!(count, 1);
+        }
+
+        // each in-memory database is separate
+        let other: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+        assert_eq!(other.keys_count().await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_connections() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        let mut tasks = vec![];
+
+        for task in 0..16 {
+            let db = db.clone();
+            tasks.push(tokio::spawn(async move {
+                for i in 0..25 {
+                    let key = format!("{task}-{i}");
+                    db.write(&key, &i).await.unwrap();
+                    let value: Option<i32> = db.read(&key).await.unwrap();
+                    assert_eq!(value, Some(i));
+
+                    let mut tx = db.transaction().await.unwrap();
+                    let count: Option<i32> = tx.read("count").await.unwrap();
+                    tx.write("count", &(count.unwrap_or(0) + 1)).await.unwrap();
+                    tx.commit().await.unwrap();
+                }
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert!(db.storage.pool.size() > 1);
+        assert_eq!(db.keys_count().await.unwrap(), 16 * 25 + 1);
+        let count: i32 = db.read("count").await.unwrap().unwrap();
+        assert_eq!(count, 16 * 25);
+    }
+
+    #[tokio::test]
+    async fn connection_options() {
+        let db: Db<UpdateInPlace> = Db::builder()
+            .in_memory()
+            .max_connections(2)
+            .busy_timeout(std::time::Duration::from_millis(250))
+            .synchronous(Synchronous::Normal)
+            .page_cache_size(-4000)
+            .pragma("user_version", "7")
+            .finish()
+            .await
+            .unwrap();
+
+        db.write("a", "1").await.unwrap();
+
+        let mut conn = db.storage.pool.acquire().await.unwrap();
+
+        for (pragma, expected) in [
+            ("busy_timeout", 250),
+            ("synchronous", 1),
+            ("cache_size", -4000),
+            ("user_version", 7),
+        ] {
+            let (value,): (i64,) = sqlx::query_as(&format!("pragma {pragma}"))
+                .fetch_one(&mut *conn)
+                .await
+                .unwrap();
+            assert_eq!(value, expected, "{pragma}");
+        }
+
+        assert_eq!(db.storage.pool.options().get_max_connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn file_options() {
+        let path =
+            std::env::temp_dir().join(format!("kvqlite-journal-mode-{}.db", std::process::id()));
+
+        let db: Db<UpdateInPlace> = Db::builder()
+            .with_db_path(&path)
+            .journal_mode(JournalMode::Delete)
+            .mmap_size(1 << 20)
+            .finish()
+            .await
+            .unwrap();
+
+        db.write("a", "1").await.unwrap();
+
+        let (journal_mode,): (String,) = sqlx::query_as("pragma journal_mode")
+            .fetch_one(&db.storage.pool)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "delete");
+
+        let (mmap_size,): (i64,) = sqlx::query_as("pragma mmap_size")
+            .fetch_one(&db.storage.pool)
+            .await
+            .unwrap();
+        assert_eq!(mmap_size, 1 << 20);
+
+        db.storage.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn read_only() {
+        let path = std::env::temp_dir().join(format!(
+            "kvqlite-read-only-update-in-place-{}.db",
+            std::process::id()
+        ));
+
+        // a read-only handle does not create the database
+        assert!(Db::<UpdateInPlace>::builder()
+            .with_db_path(&path)
+            .read_only()
+            .finish()
+            .await
+            .is_err());
+
+        let writer: Db<UpdateInPlace> = Db::builder().with_db_path(&path).finish().await.unwrap();
+        writer.write("a", "1").await.unwrap();
+        writer.write("b", "2").await.unwrap();
+
+        let reader: ReadOnlyDb<UpdateInPlace> = Db::builder()
+            .with_db_path(&path)
+            .read_only()
+            .finish()
+            .await
+            .unwrap();
+
+        let value: Option<String> = reader.read("a").await.unwrap();
+        assert_eq!(value, Some("1".to_string()));
+        assert_eq!(
+            reader.keys().await.unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+
+        writer.write("a", "3").await.unwrap();
+        let value: Option<String> = reader.read("a").await.unwrap();
+        assert_eq!(value, Some("3".to_string()));
+
+        // the connections themselves are read-only
+        let result = sqlx::query("delete from kvs")
+            .execute(&reader.db.storage.pool)
+            .await;
+        assert!(result.is_err());
+
+        writer.storage.pool.close().await;
+        reader.db.storage.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_old_formats() {
+        let path = std::env::temp_dir().join(format!(
+            "kvqlite-migrate-update-in-place-{}.db",
+            std::process::id()
+        ));
+
+        // the layout from before the metadata table
+        let pool = SqlitePool::connect_with(
+            sqlx::sqlite::SqliteConnectOptions::new()
+                .filename(&path)
+                .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+
+        sqlx::query(
+            "
+            create table kvs (
+                key blob not null primary key,
+                value blob not null,
+                inserted_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+                updated_at datetime not null default(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+            )
+            ",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        for (key, value) in [("a", "1"), ("b", "2")] {
+            sqlx::query("insert into kvs (key, value) values(?, ?)")
+                .bind(key.as_bytes())
+                .bind(Codec::Cbor.serialize(value).unwrap())
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        pool.close().await;
+
+        assert!(matches!(
+            Db::<UpdateInPlace>::builder()
+                .with_db_path(&path)
+                .read_only()
+                .finish()
+                .await,
+            Err(Error::MigrationNeeded(0))
+        ));
+
+        // a backup in the old layout is restored as it is,
+        // and migrated when it is opened
+        let restored_path = std::env::temp_dir().join(format!(
+            "kvqlite-migrate-restored-{}.db",
+            std::process::id()
+        ));
+        assert!(matches!(
+            Db::<Append>::restore(&path, &restored_path).await,
+            Err(Error::StrategyMismatch(Strategy::UpdateInPlace))
+        ));
+        Db::<UpdateInPlace>::restore(&path, &restored_path)
+            .await
+            .unwrap();
+        let restored: Db<UpdateInPlace> = Db::builder()
+            .with_db_path(&restored_path)
+            .finish()
+            .await
+            .unwrap();
+        let value: Option<String> = restored.read("b").await.unwrap();
+        assert_eq!(value, Some("2".to_string()));
+        restored.storage.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = restored_path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+
+        let db: Db<UpdateInPlace> = Db::builder().with_db_path(&path).finish().await.unwrap();
+
+        let value: Option<String> = db.read("a").await.unwrap();
+        assert_eq!(value, Some("1".to_string()));
+        assert_eq!(db.keys_count().await.unwrap(), 2);
+
+        db.bucket("other").write("a", "3").await.unwrap();
+        db.write("a", "4").await.unwrap();
+        let value: Option<String> = db.read("a").await.unwrap();
+        assert_eq!(value, Some("4".to_string()));
+
+        let meta: Vec<(String, String)> =
+            sqlx::query_as("select name, value from meta order by name")
+                .fetch_all(&db.storage.pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            meta,
+            vec![
+                ("codec".to_string(), "cbor".to_string()),
+                ("format_version".to_string(), "2".to_string()),
+                ("revision".to_string(), "3".to_string()),
+                ("strategy".to_string(), "update_in_place".to_string()),
+            ]
+        );
+
+        // a file from a newer version of kvqlite
+        sqlx::query("update meta set value = '99' where name = 'format_version'")
+            .execute(&db.storage.pool)
+            .await
+            .unwrap();
+        db.storage.pool.close().await;
+
+        assert!(matches!(
+            Db::<UpdateInPlace>::builder()
+                .with_db_path(&path)
+                .finish()
+                .await,
+            Err(Error::UnsupportedFormatVersion(99))
+        ));
+
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn into_append() {
+        let db: Db<UpdateInPlace> = Db::builder().in_memory().finish().await.unwrap();
+
+        db.write("a", "1").await.unwrap();
+        // "a" was first written long ago, and overwritten now
+        sqlx::query(
+            "
+            update kvs
+            set inserted_at = '2000-01-01 00:00:00.000', updated_at = '2000-01-01 00:00:00.000'
+            ",
+        )
+        .execute(&db.storage.pool)
+        .await
+        .unwrap();
+        db.write("a", "2").await.unwrap();
+        db.write("b", "1").await.unwrap();
+        db.bucket("other").write("a", "3").await.unwrap();
+
+        let db = db.into_append().await.unwrap();
+
+        let value: Option<String> = db.read("a").await.unwrap();
+        assert_eq!(value, Some("2".to_string()));
+        // its version is from when it was overwritten
+        let long_ago = "2001-01-01T00:00:00Z".parse().unwrap();
+        assert!(matches!(
+            db.read_as_of::<_, String>("a", long_ago).await.unwrap(),
+            AsOf::Absent
+        ));
+        let value: Option<String> = db.bucket("other").read("a").await.unwrap();
+        assert_eq!(value, Some("3".to_string()));
+        assert_eq!(db.keys().await.unwrap(), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(db.entries_count().await.unwrap(), 2);
+
+        db.write("a", "4").await.unwrap();
+        let value: Option<String> = db.read("a").await.unwrap();
+        assert_eq!(value, Some("4".to_string()));
+        assert_eq!(db.entries_count().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn strategy_mismatch() {
+        let path = std::env::temp_dir().join(format!(
+            "kvqlite-strategy-mismatch-update-in-place-{}.db",
+            std::process::id()
+        ));
+
+        let db: Db<UpdateInPlace> = Db::builder().with_db_path(&path).finish().await.unwrap();
+        db.write("a", "1").await.unwrap();
+
+        assert!(matches!(
+            Db::<Append>::builder().with_db_path(&path).finish().await,
+            Err(Error::StrategyMismatch(Strategy::UpdateInPlace))
+        ));
+
+        let db = db.into_append().await.unwrap();
+
+        let reopened: Db<Append> = Db::builder().with_db_path(&path).finish().await.unwrap();
+        let value: Option<String> = reopened.read("a").await.unwrap();
+        assert_eq!(value, Some("1".to_string()));
+
+        assert!(matches!(
+            Db::<UpdateInPlace>::builder()
+                .with_db_path(&path)
+                .finish()
+                .await,
+            Err(Error::StrategyMismatch(Strategy::Append))
+        ));
+
+        db.storage.pool.close().await;
+        reopened.storage.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn codec_mismatch() {
+        let path =
+            std::env::temp_dir().join(format!("kvqlite-codec-mismatch-{}.db", std::process::id()));
+
+        let db: Db<UpdateInPlace> = Db::builder()
+            .with_db_path(&path)
+            .codec(Codec::Json)
+            .finish()
+            .await
+            .unwrap();
+        db.write("a", "1").await.unwrap();
+
+        assert!(matches!(
+            Db::<UpdateInPlace>::builder()
+                .with_db_path(&path)
+                .codec(Codec::Cbor)
+                .finish()
+                .await,
+            Err(Error::CodecMismatch(Codec::Json))
+        ));
+
+        assert!(matches!(
+            Db::<UpdateInPlace>::builder()
+                .with_db_path(&path)
+                .codec(Codec::Cbor)
+                .read_only()
+                .finish()
+                .await,
+            Err(Error::CodecMismatch(Codec::Json))
+        ));
+
+        let reopened: Db<UpdateInPlace> = Db::builder()
+            .with_db_path(&path)
+            .codec(Codec::Json)
+            .finish()
+            .await
+            .unwrap();
+        let value: Option<String> = reopened.read("a").await.unwrap();
+        assert_eq!(value, Some("1".to_string()));
+
+        db.storage.pool.close().await;
+        reopened.storage.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn ambiguous_strategy() {
+        let path = std::env::temp_dir().join(format!(
+            "kvqlite-ambiguous-strategy-{}.db",
+            std::process::id()
+        ));
+
+        let db: Db<UpdateInPlace> = Db::builder().with_db_path(&path).finish().await.unwrap();
+        db.write("a", "1").await.unwrap();
+
+        // key/values in both strategies' tables, and no recorded strategy
+        let mut conn = db.storage.pool.acquire().await.unwrap();
+        crate::storage::append::create_tables(&mut conn)
+            .await
+            .unwrap();
+        sqlx::query("insert into keys (key) values(x'61')")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query("delete from meta where name = 'strategy'")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+        db.storage.pool.close().await;
+
+        assert!(matches!(
+            Db::<UpdateInPlace>::builder()
+                .with_db_path(&path)
+                .finish()
+                .await,
+            Err(Error::AmbiguousStrategy)
+        ));
+        assert!(matches!(
+            Db::<Append>::builder().with_db_path(&path).finish().await,
+            Err(Error::AmbiguousStrategy)
+        ));
+
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn json_is_queryable() {
+        let db: Db<UpdateInPlace> = Db::builder()
+            .in_memory()
+            .codec(Codec::Json)
+            .finish()
+            .await
+            .unwrap();
+
+        db.write("joe", &serde_json::json!({"name": "joe", "age": 42}))
+            .await
+            .unwrap();
+        db.write("mike", &serde_json::json!({"name": "mike", "age": 30}))
+            .await
+            .unwrap();
+
+        let names: Vec<(String,)> = sqlx::query_as(
+            "
+            select
+                json_extract(value, '$.name')
+            from kvs
+            where json_extract(value, '$.age') > 40
+            ",
+        )
+        .fetch_all(&db.storage.pool)
+        .await
+        .unwrap();
+
+        assert_eq!(names, vec![("joe".to_string(),)]);
+    }
 }
@@ -0,0 +1,125 @@
+use crate::storage::{prefix_end, Storage};
+use crate::watch::Pending;
+use crate::{begin_immediate, bound_as_ref, Error, Expiry};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sqlx::pool::PoolConnection;
+use sqlx::Sqlite;
+use std::ops::{Bound, RangeBounds};
+
+/// a set of reads and writes that commit or roll back together.
+/// dropping a transaction without committing it rolls it back.
+pub struct Transaction<'a, T>
+where
+    T: Storage,
+{
+    storage: &'a T,
+    tx: sqlx::Transaction<'static, Sqlite>,
+    pending: Pending,
+}
+
+impl<'a, T> Transaction<'a, T>
+where
+    T: Storage,
+{
+    pub(crate) async fn begin(storage: &'a T, conn: PoolConnection<Sqlite>) -> Result<Self, Error> {
+        let tx = begin_immediate::begin_owned(conn).await?;
+
+        Ok(Self {
+            storage,
+            tx,
+            pending: Pending::default(),
+        })
+    }
+
+    /// write a key/value
+    pub async fn write<K, V>(&mut self, key: &K, value: &V) -> Result<(), Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        let value = self.storage.codec().serialize(value)?;
+        self.pending
+            .write(self.storage, &mut self.tx, key, value, None)
+            .await?;
+        Ok(())
+    }
+
+    /// write a key/value that reads as missing once `expiry` passes
+    pub async fn write_with_expiry<K, V>(
+        &mut self,
+        key: &K,
+        value: &V,
+        expiry: Expiry,
+    ) -> Result<(), Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        let value = self.storage.codec().serialize(value)?;
+        self.pending
+            .write(self.storage, &mut self.tx, key, value, expiry.expires_at())
+            .await?;
+        Ok(())
+    }
+
+    /// read a value, including writes made earlier in this transaction
+    pub async fn read<K, V>(&mut self, key: &K) -> Result<Option<V>, Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+    {
+        self.storage.read(&mut self.tx, key).await
+    }
+
+    /// delete a key/value
+    pub async fn delete<K>(&mut self, key: &K) -> Result<(), Error>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        self.pending.delete(self.storage, &mut self.tx, key).await
+    }
+
+    /// read the key/values with keys in the given range, in key order
+    pub async fn range<K, V, R>(&mut self, range: R) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        K: AsRef<[u8]>,
+        V: DeserializeOwned,
+        R: RangeBounds<K>,
+    {
+        let start = range.start_bound().map(|k| k.as_ref());
+        let end = range.end_bound().map(|k| k.as_ref());
+        self.storage.range(&mut self.tx, start, end, None).await
+    }
+
+    /// read the key/values with keys starting with `prefix`, in key order
+    pub async fn prefix<P, V>(&mut self, prefix: &P) -> Result<Vec<(Vec<u8>, V)>, Error>
+    where
+        P: AsRef<[u8]> + ?Sized,
+        V: DeserializeOwned,
+    {
+        let prefix = prefix.as_ref();
+        let end = prefix_end(prefix);
+        self.storage
+            .range(
+                &mut self.tx,
+                Bound::Included(prefix),
+                bound_as_ref(&end),
+                None,
+            )
+            .await
+    }
+
+    /// make this transaction's writes visible to everyone
+    pub async fn commit(self) -> Result<(), Error> {
+        self.tx.commit().await?;
+        self.pending.publish(self.storage);
+        Ok(())
+    }
+
+    /// discard this transaction's writes
+    pub async fn rollback(self) -> Result<(), Error> {
+        self.tx.rollback().await?;
+        Ok(())
+    }
+}
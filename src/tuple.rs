@@ -0,0 +1,556 @@
+use crate::key::Key;
+use crate::Error;
+
+/// a key made of typed elements, encoded like FoundationDB's tuple layer.
+///
+/// packed tuples sort element by element, each element in its type's
+/// natural order, and a packed tuple is a prefix of the packed form of
+/// every longer tuple that starts with the same elements.
+/// so `Db::prefix(&tuple.pack())` reads the keys that extend `tuple`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tuple(pub Vec<Element>);
+
+/// one element of a `Tuple`.
+/// elements of different types sort in the order of the variants here.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Element {
+    Null,
+    Bytes(Vec<u8>),
+    String(String),
+    Tuple(Tuple),
+    /// integers of either sign share one encoding, so they sort numerically
+    /// with each other. non-negative integers unpack as `Int` if they fit
+    /// in an `i64` and as `UInt` if they do not.
+    Int(i64),
+    UInt(u64),
+    Float(f32),
+    Double(f64),
+    Bool(bool),
+    /// the 16 bytes of a UUID
+    Uuid([u8; 16]),
+}
+
+const NULL: u8 = 0x00;
+const BYTES: u8 = 0x01;
+const STRING: u8 = 0x02;
+const NESTED: u8 = 0x05;
+const INT_ZERO: u8 = 0x14;
+const FLOAT: u8 = 0x20;
+const DOUBLE: u8 = 0x21;
+const FALSE: u8 = 0x26;
+const TRUE: u8 = 0x27;
+const UUID: u8 = 0x30;
+
+/// a null within a nested tuple or bytes is followed by this,
+/// to tell it apart from the null that ends them
+const ESCAPE: u8 = 0xff;
+
+impl Tuple {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add an element to the end of the tuple
+    pub fn push<E>(mut self, element: E) -> Self
+    where
+        E: Into<Element>,
+    {
+        self.0.push(element.into());
+        self
+    }
+
+    pub fn elements(&self) -> &[Element] {
+        &self.0
+    }
+
+    /// encode the tuple as a key
+    pub fn pack(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for element in &self.0 {
+            encode_element(element, &mut out, false);
+        }
+        out
+    }
+
+    /// decode a key packed by `pack`
+    pub fn unpack(bytes: &[u8]) -> Result<Self, Error> {
+        let mut elements = vec![];
+        let mut offset = 0;
+
+        while offset < bytes.len() {
+            let (element, len) = decode_element(&bytes[offset..], false)?;
+            elements.push(element);
+            offset += len;
+        }
+
+        Ok(Tuple(elements))
+    }
+}
+
+impl Key for Tuple {
+    fn to_element(&self) -> Element {
+        Element::Tuple(self.clone())
+    }
+
+    fn from_element(element: Element) -> Result<Self, Error> {
+        match element {
+            Element::Tuple(tuple) => Ok(tuple),
+            _ => Err(Error::KeyDecoding),
+        }
+    }
+
+    fn encode_key(&self) -> Vec<u8> {
+        self.pack()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Self, Error> {
+        Tuple::unpack(bytes)
+    }
+}
+
+impl Key for Element {
+    // an element on its own encodes like a one element tuple
+    fn to_element(&self) -> Element {
+        self.clone()
+    }
+
+    fn from_element(element: Element) -> Result<Self, Error> {
+        Ok(element)
+    }
+}
+
+fn encode_element(element: &Element, out: &mut Vec<u8>, nested: bool) {
+    match element {
+        Element::Null => {
+            out.push(NULL);
+            if nested {
+                out.push(ESCAPE);
+            }
+        }
+        Element::Bytes(bytes) => {
+            out.push(BYTES);
+            encode_bytes(bytes, out);
+        }
+        Element::String(string) => {
+            out.push(STRING);
+            encode_bytes(string.as_bytes(), out);
+        }
+        Element::Tuple(tuple) => {
+            out.push(NESTED);
+            for element in &tuple.0 {
+                encode_element(element, out, true);
+            }
+            out.push(NULL);
+        }
+        Element::Int(int) => {
+            if *int < 0 {
+                encode_negative(int.unsigned_abs(), out);
+            } else {
+                encode_positive(*int as u64, out);
+            }
+        }
+        Element::UInt(uint) => encode_positive(*uint, out),
+        Element::Float(float) => {
+            out.push(FLOAT);
+            out.extend_from_slice(&float_bits(float.to_bits() as u64, 32).to_be_bytes()[4..]);
+        }
+        Element::Double(double) => {
+            out.push(DOUBLE);
+            out.extend_from_slice(&float_bits(double.to_bits(), 64).to_be_bytes());
+        }
+        Element::Bool(false) => out.push(FALSE),
+        Element::Bool(true) => out.push(TRUE),
+        Element::Uuid(uuid) => {
+            out.push(UUID);
+            out.extend_from_slice(uuid);
+        }
+    }
+}
+
+/// decode one element from the start of `bytes`,
+/// returning it and the number of bytes it took up
+fn decode_element(bytes: &[u8], nested: bool) -> Result<(Element, usize), Error> {
+    let (&code, rest) = bytes.split_first().ok_or(Error::KeyDecoding)?;
+
+    let (element, len) = match code {
+        NULL if nested && rest.first() == Some(&ESCAPE) => (Element::Null, 1),
+        NULL if nested => return Err(Error::KeyDecoding),
+        NULL => (Element::Null, 0),
+        BYTES => {
+            let (bytes, len) = decode_bytes(rest)?;
+            (Element::Bytes(bytes), len)
+        }
+        STRING => {
+            let (bytes, len) = decode_bytes(rest)?;
+            let string = String::from_utf8(bytes).map_err(|_| Error::KeyDecoding)?;
+            (Element::String(string), len)
+        }
+        NESTED => {
+            let mut elements = vec![];
+            let mut offset = 0;
+
+            loop {
+                match (rest.get(offset), rest.get(offset + 1)) {
+                    (Some(&NULL), Some(&ESCAPE)) => (),
+                    (Some(&NULL), _) => break,
+                    (None, _) => return Err(Error::KeyDecoding),
+                    _ => (),
+                }
+
+                let (element, len) = decode_element(&rest[offset..], true)?;
+                elements.push(element);
+                offset += len;
+            }
+
+            (Element::Tuple(Tuple(elements)), offset + 1)
+        }
+        0x0c..=0x1c => {
+            let len = code.abs_diff(INT_ZERO) as usize;
+            let mut be_bytes = if code < INT_ZERO {
+                [0xff; 8]
+            } else {
+                [0x00; 8]
+            };
+            be_bytes[8 - len..].copy_from_slice(rest.get(..len).ok_or(Error::KeyDecoding)?);
+            let magnitude = u64::from_be_bytes(be_bytes);
+
+            let element = if code < INT_ZERO {
+                let magnitude = !magnitude;
+                if magnitude > i64::MIN.unsigned_abs() {
+                    return Err(Error::KeyDecoding);
+                }
+                Element::Int((magnitude as i64).wrapping_neg())
+            } else if let Ok(int) = i64::try_from(magnitude) {
+                Element::Int(int)
+            } else {
+                Element::UInt(magnitude)
+            };
+
+            (element, len)
+        }
+        FLOAT => {
+            let be_bytes = rest.get(..4).ok_or(Error::KeyDecoding)?;
+            let bits = u32::from_be_bytes(be_bytes.try_into().unwrap());
+            let bits = float_bits_from(bits as u64, 32) as u32;
+            (Element::Float(f32::from_bits(bits)), 4)
+        }
+        DOUBLE => {
+            let be_bytes = rest.get(..8).ok_or(Error::KeyDecoding)?;
+            let bits = u64::from_be_bytes(be_bytes.try_into().unwrap());
+            (
+                Element::Double(f64::from_bits(float_bits_from(bits, 64))),
+                8,
+            )
+        }
+        FALSE => (Element::Bool(false), 0),
+        TRUE => (Element::Bool(true), 0),
+        UUID => {
+            let uuid = rest.get(..16).ok_or(Error::KeyDecoding)?;
+            (Element::Uuid(uuid.try_into().unwrap()), 16)
+        }
+        _ => return Err(Error::KeyDecoding),
+    };
+
+    Ok((element, len + 1))
+}
+
+/// nulls are escaped and the bytes end with a null
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    for byte in bytes {
+        out.push(*byte);
+        if *byte == NULL {
+            out.push(ESCAPE);
+        }
+    }
+    out.push(NULL);
+}
+
+fn decode_bytes(bytes: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+    let mut decoded = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (NULL, Some(&ESCAPE)) => {
+                decoded.push(NULL);
+                i += 2;
+            }
+            (NULL, _) => return Ok((decoded, i + 1)),
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    Err(Error::KeyDecoding)
+}
+
+/// integers are a code saying how many bytes follow,
+/// then their magnitude in that many big-endian bytes
+fn encode_positive(int: u64, out: &mut Vec<u8>) {
+    let len = 8 - int.leading_zeros() as usize / 8;
+    out.push(INT_ZERO + len as u8);
+    out.extend_from_slice(&int.to_be_bytes()[8 - len..]);
+}
+
+/// negative integers use the ones' complement of their magnitude,
+/// so larger magnitudes sort first
+fn encode_negative(magnitude: u64, out: &mut Vec<u8>) {
+    let len = 8 - magnitude.leading_zeros() as usize / 8;
+    out.push(INT_ZERO - len as u8);
+    out.extend_from_slice(&(!magnitude).to_be_bytes()[8 - len..]);
+}
+
+/// flip the sign bit of positive floats and every bit of negative ones,
+/// so they sort numerically as unsigned integers
+fn float_bits(bits: u64, width: u32) -> u64 {
+    let sign = 1 << (width - 1);
+    let mask = if width == 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    };
+
+    if bits & sign == 0 {
+        bits | sign
+    } else {
+        !bits & mask
+    }
+}
+
+fn float_bits_from(bits: u64, width: u32) -> u64 {
+    let sign = 1 << (width - 1);
+    let mask = if width == 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    };
+
+    if bits & sign == 0 {
+        !bits & mask
+    } else {
+        bits & !sign
+    }
+}
+
+impl From<&str> for Element {
+    fn from(string: &str) -> Self {
+        Element::String(string.to_string())
+    }
+}
+
+impl From<String> for Element {
+    fn from(string: String) -> Self {
+        Element::String(string)
+    }
+}
+
+impl From<Vec<u8>> for Element {
+    fn from(bytes: Vec<u8>) -> Self {
+        Element::Bytes(bytes)
+    }
+}
+
+impl From<Tuple> for Element {
+    fn from(tuple: Tuple) -> Self {
+        Element::Tuple(tuple)
+    }
+}
+
+impl From<i64> for Element {
+    fn from(int: i64) -> Self {
+        Element::Int(int)
+    }
+}
+
+impl From<i32> for Element {
+    fn from(int: i32) -> Self {
+        Element::Int(int.into())
+    }
+}
+
+impl From<u64> for Element {
+    fn from(uint: u64) -> Self {
+        i64::try_from(uint).map_or(Element::UInt(uint), Element::Int)
+    }
+}
+
+impl From<i16> for Element {
+    fn from(int: i16) -> Self {
+        Element::Int(int.into())
+    }
+}
+
+impl From<i8> for Element {
+    fn from(int: i8) -> Self {
+        Element::Int(int.into())
+    }
+}
+
+impl From<u32> for Element {
+    fn from(uint: u32) -> Self {
+        Element::Int(uint.into())
+    }
+}
+
+impl From<u16> for Element {
+    fn from(uint: u16) -> Self {
+        Element::Int(uint.into())
+    }
+}
+
+impl From<u8> for Element {
+    fn from(uint: u8) -> Self {
+        Element::Int(uint.into())
+    }
+}
+
+impl From<f32> for Element {
+    fn from(float: f32) -> Self {
+        Element::Float(float)
+    }
+}
+
+impl From<f64> for Element {
+    fn from(double: f64) -> Self {
+        Element::Double(double)
+    }
+}
+
+impl From<bool> for Element {
+    fn from(b: bool) -> Self {
+        Element::Bool(b)
+    }
+}
+
+/// a rust tuple of elements is the `Tuple` of them
+macro_rules! tuple_from {
+    ($($t:ident),*) => {
+        impl<$($t),*> From<($($t,)*)> for Tuple
+        where
+            $($t: Into<Element>,)*
+        {
+            #[allow(non_snake_case)]
+            fn from(($($t,)*): ($($t,)*)) -> Self {
+                Tuple(vec![$($t.into()),*])
+            }
+        }
+    };
+}
+
+tuple_from!(A);
+tuple_from!(A, B);
+tuple_from!(A, B, C);
+tuple_from!(A, B, C, D);
+tuple_from!(A, B, C, D, E);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_sorted(tuples: &[Tuple]) {
+        let packed: Vec<Vec<u8>> = tuples.iter().map(Tuple::pack).collect();
+
+        let mut sorted = packed.clone();
+        sorted.sort();
+        assert_eq!(packed, sorted);
+
+        for (tuple, packed) in tuples.iter().zip(&packed) {
+            assert_eq!(&Tuple::unpack(packed).unwrap(), tuple);
+        }
+    }
+
+    fn one<E>(element: E) -> Tuple
+    where
+        E: Into<Element>,
+    {
+        Tuple::new().push(element)
+    }
+
+    #[test]
+    fn integers() {
+        assert_sorted(&[
+            one(i64::MIN),
+            one(-65536),
+            one(-256),
+            one(-255),
+            one(-1),
+            one(0),
+            one(1),
+            one(255),
+            one(256),
+            one(i64::MAX),
+            one(u64::MAX),
+        ]);
+        assert_eq!(one(0).pack(), vec![0x14]);
+        assert_eq!(one(-1).pack(), vec![0x13, 0xfe]);
+        assert_eq!(one(256).pack(), vec![0x16, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn floats() {
+        assert_sorted(&[
+            one(f64::NEG_INFINITY),
+            one(-1.5),
+            one(-0.0),
+            one(0.0),
+            one(1.5),
+            one(f64::INFINITY),
+        ]);
+        assert_sorted(&[one(-2.5f32), one(0.0f32), one(f32::MAX)]);
+    }
+
+    #[test]
+    fn strings_and_bytes() {
+        assert_sorted(&[
+            one(vec![0x00]),
+            one(vec![0x00, 0x00]),
+            one(vec![0x01]),
+            one(""),
+            one("a"),
+            one("a\0b"),
+            one("ab"),
+        ]);
+        assert_eq!(one("a\0").pack(), vec![0x02, b'a', 0x00, 0xff, 0x00]);
+    }
+
+    #[test]
+    fn nested_and_mixed() {
+        let id = [7; 16];
+        assert_sorted(&[
+            Tuple::new().push(Element::Null),
+            Tuple::new().push("user").push(one(Element::Null).push(1)),
+            Tuple::new().push("user").push(one(1)),
+            Tuple::new().push("user").push(one(1)).push(true),
+            Tuple::new().push("user").push(1),
+            Tuple::new().push("user").push(2.5),
+            Tuple::new().push("user").push(false),
+            Tuple::new().push("user").push(Element::Uuid(id)),
+        ]);
+    }
+
+    #[test]
+    fn prefixes() {
+        let prefix = Tuple::new().push("user").push(1);
+        let tuple = prefix.clone().push("name");
+        assert!(tuple.pack().starts_with(&prefix.pack()));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(Tuple::unpack(&[0xff]), Err(Error::KeyDecoding)));
+        assert!(matches!(
+            Tuple::unpack(&[STRING, b'a']),
+            Err(Error::KeyDecoding)
+        ));
+        assert!(matches!(
+            Tuple::unpack(&[0x16, 0x01]),
+            Err(Error::KeyDecoding)
+        ));
+        assert!(matches!(
+            Tuple::unpack(&[NESTED, 0x14]),
+            Err(Error::KeyDecoding)
+        ));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::key::{self, Key};
+use crate::storage::Storage;
+use crate::{Db, Error, Expiry};
+use futures::{Stream, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+/// a handle to a database whose keys are all `K` and whose values are all `V`.
+/// keys are encoded so that scans return them in `K`'s order.
+#[derive(Clone, Debug)]
+pub struct TypedDb<K, V, S>
+where
+    S: Storage,
+{
+    db: Db<S>,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, S> TypedDb<K, V, S>
+where
+    K: Key,
+    V: Serialize + DeserializeOwned,
+    S: Storage,
+{
+    pub fn new(db: Db<S>) -> Self {
+        Self {
+            db,
+            types: PhantomData,
+        }
+    }
+
+    /// the untyped handle
+    pub fn db(&self) -> &Db<S> {
+        &self.db
+    }
+
+    /// write a key/value
+    pub async fn write(&self, key: &K, value: &V) -> Result<(), Error> {
+        self.db.write(&key::encode(key), value).await
+    }
+
+    /// write a key/value that reads as missing once `expiry` passes
+    pub async fn write_with_expiry(&self, key: &K, value: &V, expiry: Expiry) -> Result<(), Error> {
+        self.db
+            .write_with_expiry(&key::encode(key), value, expiry)
+            .await
+    }
+
+    /// read a value
+    pub async fn read(&self, key: &K) -> Result<Option<V>, Error> {
+        self.db.read(&key::encode(key)).await
+    }
+
+    /// delete a key/value
+    pub async fn delete(&self, key: &K) -> Result<(), Error> {
+        self.db.delete(&key::encode(key)).await
+    }
+
+    /// read the key/values with keys in the given range, in key order
+    pub async fn range<R>(&self, range: R) -> Result<Vec<(K, V)>, Error>
+    where
+        R: RangeBounds<K>,
+    {
+        let start = range.start_bound().map(key::encode);
+        let end = range.end_bound().map(key::encode);
+
+        self.db
+            .range::<Vec<u8>, V, _>((start, end))
+            .await?
+            .into_iter()
+            .map(|(key, value)| Ok((key::decode(&key)?, value)))
+            .collect()
+    }
+
+    /// get the current keys, in key order
+    pub async fn keys(&self) -> Result<Vec<K>, Error> {
+        self.stream_keys().try_collect().await
+    }
+
+    /// get the current number of keys
+    pub async fn keys_count(&self) -> Result<u64, Error> {
+        self.db.keys_count().await
+    }
+
+    /// stream the current keys in key order, like `Db::stream_keys`
+    pub fn stream_keys(&self) -> impl Stream<Item = Result<K, Error>> + '_ {
+        self.db
+            .stream_keys()
+            .and_then(|key| async move { key::decode(&key) })
+    }
+
+    /// stream the current key/values in key order, like `Db::stream_entries`
+    pub fn stream_entries(&self) -> impl Stream<Item = Result<(K, V), Error>> + '_ {
+        self.db
+            .stream_entries()
+            .and_then(|(key, value)| async move { Ok((key::decode(&key)?, value)) })
+    }
+}
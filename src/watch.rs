@@ -0,0 +1,314 @@
+use crate::codec::EncodedValue;
+use crate::storage::Storage;
+use crate::{changes, index, Codec, Error, Revision};
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use serde::de::DeserializeOwned;
+use sqlx::SqliteConnection;
+use tokio::sync::broadcast;
+
+/// a committed change to a key
+#[derive(Clone, Debug)]
+pub struct Event {
+    kind: EventKind,
+    bucket: String,
+    key: Vec<u8>,
+    old_value: Option<Vec<u8>>,
+    new_value: Option<Vec<u8>>,
+    codec: Codec,
+}
+
+impl Event {
+    /// whether the key was written or deleted
+    pub fn kind(&self) -> EventKind {
+        self.kind
+    }
+
+    /// the bucket of the key that changed
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// the key that changed
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// the value before the change, if the key had one
+    pub fn old_value<V>(&self) -> Result<Option<V>, Error>
+    where
+        V: DeserializeOwned,
+    {
+        self.old_value
+            .as_deref()
+            .map(|value_bytes| self.codec.deserialize(value_bytes))
+            .transpose()
+    }
+
+    /// the value after the change, or `None` for a delete
+    pub fn new_value<V>(&self) -> Result<Option<V>, Error>
+    where
+        V: DeserializeOwned,
+    {
+        self.new_value
+            .as_deref()
+            .map(|value_bytes| self.codec.deserialize(value_bytes))
+            .transpose()
+    }
+}
+
+/// the kind of change an event records
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Put,
+    Delete,
+}
+
+/// the keys a watcher is interested in
+enum Filter {
+    Key(Vec<u8>),
+    Prefix(Vec<u8>),
+}
+
+impl Filter {
+    fn matches(&self, key: &[u8]) -> bool {
+        match self {
+            Filter::Key(k) => key == k.as_slice(),
+            Filter::Prefix(prefix) => key.starts_with(prefix),
+        }
+    }
+}
+
+/// the events in `bucket` matching `filter`, from the time of subscribing
+fn subscribe(
+    receiver: broadcast::Receiver<Event>,
+    bucket: String,
+    filter: Filter,
+) -> impl Stream<Item = Result<Event, Error>> {
+    futures::stream::unfold((receiver, filter), move |(mut receiver, filter)| {
+        let bucket = bucket.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if event.bucket == bucket && filter.matches(&event.key) => {
+                        return Some((Ok(event), (receiver, filter)))
+                    }
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        return Some((Err(Error::Lagged(missed)), (receiver, filter)))
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+}
+
+pub(crate) fn watch_key<T>(storage: &T, key: &[u8]) -> impl Stream<Item = Result<Event, Error>>
+where
+    T: Storage,
+{
+    subscribe(
+        storage.events().subscribe(),
+        storage.bucket().to_string(),
+        Filter::Key(key.to_vec()),
+    )
+}
+
+pub(crate) fn watch_prefix<T>(
+    storage: &T,
+    prefix: &[u8],
+) -> impl Stream<Item = Result<Event, Error>>
+where
+    T: Storage,
+{
+    subscribe(
+        storage.events().subscribe(),
+        storage.bucket().to_string(),
+        Filter::Prefix(prefix.to_vec()),
+    )
+}
+
+/// events for the changes made in a transaction,
+/// sent to watchers once it commits.
+/// events and old values are recorded whether or not anything is watching,
+/// since a watcher can subscribe before the transaction commits.
+/// changes are also recorded in the change log, if there is one.
+#[derive(Default)]
+pub(crate) struct Pending {
+    events: Vec<Event>,
+    /// whether the database has a change log,
+    /// checked by the transaction's first change
+    change_log: Option<bool>,
+}
+
+impl Pending {
+    /// write a key/value, recording the change
+    pub(crate) async fn write<T, K>(
+        &mut self,
+        storage: &T,
+        conn: &mut SqliteConnection,
+        key: &K,
+        value: EncodedValue,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Revision, Error>
+    where
+        T: Storage,
+        K: AsRef<[u8]> + ?Sized,
+    {
+        let old_value = self.old_value(storage, conn, key).await?;
+
+        let revision = storage.write(conn, key, &value, expires_at).await?;
+
+        index::update(storage, conn, key.as_ref(), value.as_bytes()).await?;
+
+        if self.change_log(conn).await? {
+            changes::record(
+                conn,
+                EventKind::Put,
+                storage.bucket(),
+                key.as_ref(),
+                Some(value.as_bytes()),
+            )
+            .await?;
+        }
+
+        self.events.push(Event {
+            kind: EventKind::Put,
+            bucket: storage.bucket().to_string(),
+            key: key.as_ref().to_vec(),
+            old_value,
+            new_value: Some(value.into_bytes()),
+            codec: storage.codec(),
+        });
+
+        Ok(revision)
+    }
+
+    /// delete a key, recording the change if it existed
+    pub(crate) async fn delete<T, K>(
+        &mut self,
+        storage: &T,
+        conn: &mut SqliteConnection,
+        key: &K,
+    ) -> Result<(), Error>
+    where
+        T: Storage,
+        K: AsRef<[u8]> + ?Sized,
+    {
+        let old_value = self.old_value(storage, conn, key).await?;
+
+        storage.delete(conn, key).await?;
+
+        index::remove(conn, storage.bucket(), key.as_ref()).await?;
+
+        self.deleted(storage, conn, key, old_value).await
+    }
+
+    /// delete every key in the bucket, recording the changes
+    pub(crate) async fn clear<T>(
+        &mut self,
+        storage: &T,
+        conn: &mut SqliteConnection,
+    ) -> Result<(), Error>
+    where
+        T: Storage,
+    {
+        let mut old_values = vec![];
+
+        for key in storage.keys(conn).await? {
+            let old_value = self.old_value(storage, conn, &key).await?;
+            old_values.push((key, old_value));
+        }
+
+        storage.clear(conn).await?;
+
+        index::clear(conn, storage.bucket(), None).await?;
+
+        for (key, old_value) in old_values {
+            self.deleted(storage, conn, &key, old_value).await?;
+        }
+
+        Ok(())
+    }
+
+    /// the current value of a key
+    pub(crate) async fn old_value<T, K>(
+        &self,
+        storage: &T,
+        conn: &mut SqliteConnection,
+        key: &K,
+    ) -> Result<Option<Vec<u8>>, Error>
+    where
+        T: Storage,
+        K: AsRef<[u8]> + ?Sized,
+    {
+        Ok(storage
+            .current(conn, key)
+            .await?
+            .map(|(value_bytes, _)| value_bytes))
+    }
+
+    /// record that a key with `old_value` was deleted.
+    /// deleting a missing key is not a change.
+    pub(crate) async fn deleted<T, K>(
+        &mut self,
+        storage: &T,
+        conn: &mut SqliteConnection,
+        key: &K,
+        old_value: Option<Vec<u8>>,
+    ) -> Result<(), Error>
+    where
+        T: Storage,
+        K: AsRef<[u8]> + ?Sized,
+    {
+        if old_value.is_none() {
+            return Ok(());
+        }
+
+        if self.change_log(conn).await? {
+            changes::record(
+                conn,
+                EventKind::Delete,
+                storage.bucket(),
+                key.as_ref(),
+                None,
+            )
+            .await?;
+        }
+
+        self.events.push(Event {
+            kind: EventKind::Delete,
+            bucket: storage.bucket().to_string(),
+            key: key.as_ref().to_vec(),
+            old_value,
+            new_value: None,
+            codec: storage.codec(),
+        });
+
+        Ok(())
+    }
+
+    /// whether changes are recorded in the change log
+    async fn change_log(&mut self, conn: &mut SqliteConnection) -> Result<bool, Error> {
+        if let Some(change_log) = self.change_log {
+            return Ok(change_log);
+        }
+
+        let change_log = changes::exists(conn).await?;
+        self.change_log = Some(change_log);
+
+        Ok(change_log)
+    }
+
+    /// send the recorded events. call only after committing.
+    pub(crate) fn publish<T>(self, storage: &T)
+    where
+        T: Storage,
+    {
+        for event in self.events {
+            // no receivers is not an error
+            let _ = storage.events().send(event);
+        }
+    }
+}